@@ -0,0 +1,367 @@
+//! Addresses of upstream agents, and connecting to them
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    net::TcpStream,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::{fs::FileTypeExt, net::UnixStream},
+    },
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::{
+    agent::Session,
+    client,
+    error::AgentError,
+    proto::{
+        AddIdentity, AddIdentityConstrained, AddSmartcardKeyConstrained, Extension, Identity,
+        RemoveIdentity, SignRequest, SmartcardKey,
+    },
+    ssh_key::Signature,
+};
+use tokio::sync::Mutex;
+
+use crate::verify::{self, Verify};
+
+/// How long connecting to an agent over TCP may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const UNIX_PREFIX: &str = "unix:";
+const UNIX_ABSTRACT_PREFIX: &str = "unix-abstract:";
+const TCP_PREFIX: &str = "tcp:";
+const FD_PREFIX: &str = "fd:";
+
+/// A single connection shared between sessions, by locking it for each request
+#[derive(Clone)]
+struct SharedSession(Arc<Mutex<Box<dyn Session>>>);
+
+/// Connections over inherited file descriptors, which can only be opened once per process, so
+/// they are shared by every upstream using the descriptor and outlive reloading the configuration
+static INHERITED: LazyLock<std::sync::Mutex<HashMap<RawFd, SharedSession>>> =
+    LazyLock::new(Default::default);
+
+/// Address of an upstream agent
+///
+/// Written as a URI such as `unix:/path`, `unix-abstract:name`, `tcp:host:port`, or `fd:N`, or as
+/// a plain filesystem path to a Unix socket.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum UpstreamAddress {
+    /// Unix socket on the filesystem
+    Unix(PathBuf),
+    /// Unix socket in the Linux abstract namespace
+    UnixAbstract(String),
+    /// TCP `host:port`
+    Tcp(String),
+    /// Connected socket inherited from the parent process, such as a supervisor
+    Fd(RawFd),
+}
+
+/// Error parsing an [`UpstreamAddress`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressParseError(String);
+
+impl fmt::Display for AddressParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for AddressParseError {}
+
+impl FromStr for UpstreamAddress {
+    type Err = AddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let non_empty = |rest: &str, what: &str| {
+            if rest.is_empty() {
+                Err(AddressParseError(format!("missing {what} in {s:?}")))
+            } else {
+                Ok(rest.to_string())
+            }
+        };
+
+        if let Some(path) = s.strip_prefix(UNIX_PREFIX) {
+            Ok(Self::Unix(non_empty(path, "socket path")?.into()))
+        } else if let Some(name) = s.strip_prefix(UNIX_ABSTRACT_PREFIX) {
+            Ok(Self::UnixAbstract(non_empty(name, "socket name")?))
+        } else if let Some(addr) = s.strip_prefix(TCP_PREFIX) {
+            let addr = non_empty(addr, "host and port")?;
+            let has_port = addr
+                .rsplit_once(':')
+                .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+            if !has_port {
+                return Err(AddressParseError(format!("missing TCP port in {s:?}")));
+            }
+            Ok(Self::Tcp(addr))
+        } else if let Some(fd) = s.strip_prefix(FD_PREFIX) {
+            fd.parse::<u16>()
+                .map(|fd| Self::Fd(fd.into()))
+                .map_err(|_| AddressParseError(format!("invalid file descriptor in {s:?}")))
+        } else {
+            Ok(Self::Unix(s.into()))
+        }
+    }
+}
+
+impl TryFrom<String> for UpstreamAddress {
+    type Error = AddressParseError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<UpstreamAddress> for String {
+    fn from(value: UpstreamAddress) -> Self {
+        value.to_string()
+    }
+}
+
+impl From<PathBuf> for UpstreamAddress {
+    fn from(value: PathBuf) -> Self {
+        Self::Unix(value)
+    }
+}
+
+impl fmt::Display for UpstreamAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Written as a plain path, because that's how most configurations spell it
+            Self::Unix(path) => path.display().fmt(f),
+            Self::UnixAbstract(name) => write!(f, "{UNIX_ABSTRACT_PREFIX}{name}"),
+            Self::Tcp(addr) => write!(f, "{TCP_PREFIX}{addr}"),
+            Self::Fd(fd) => write!(f, "{FD_PREFIX}{fd}"),
+        }
+    }
+}
+
+impl UpstreamAddress {
+    /// Connect to the agent, after checking that a Unix socket is trustworthy as `verify`
+    /// requires
+    pub(crate) async fn connect(&self, verify: Verify) -> Result<Box<dyn Session>, AgentError> {
+        let stream = match self {
+            Self::Unix(path) => {
+                if verify != Verify::Nothing {
+                    verify::check_socket(path)?;
+                }
+                let stream = UnixStream::connect(path)?;
+                if verify == Verify::PeerCredentials {
+                    verify::check_peer(&stream, self)?;
+                }
+                stream.into()
+            }
+            Self::UnixAbstract(name) => {
+                // Abstract sockets have no owner, so the agent itself is checked instead
+                let stream = connect_abstract(name)?;
+                if verify != Verify::Nothing {
+                    verify::check_peer(&stream, self)?;
+                }
+                stream.into()
+            }
+            Self::Tcp(addr) => connect_tcp(addr, CONNECT_TIMEOUT).await?.into(),
+            Self::Fd(fd) => return connect_inherited(*fd),
+        };
+        let client = client::connect(stream).map_err(|e| {
+            AgentError::Other(format!("Failed to connect to agent at {}: {}", self, e).into())
+        })?;
+        log::trace!("Connected to upstream agent at: {}", self);
+        Ok(client)
+    }
+}
+
+/// Connect to `addr` without blocking other requests, giving up after `timeout`
+async fn connect_tcp(addr: &str, timeout: Duration) -> std::io::Result<TcpStream> {
+    let stream = tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("timed out connecting to {addr}"),
+            )
+        })??;
+    stream.set_nodelay(true)?;
+    stream.into_std()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn connect_abstract(name: &str) -> std::io::Result<UnixStream> {
+    #[cfg(target_os = "android")]
+    use std::os::android::net::SocketAddrExt;
+    #[cfg(target_os = "linux")]
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+    UnixStream::connect_addr(&addr)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn connect_abstract(_name: &str) -> std::io::Result<UnixStream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract Unix sockets are only supported on Linux",
+    ))
+}
+
+fn connect_inherited(fd: RawFd) -> Result<Box<dyn Session>, AgentError> {
+    let mut inherited = INHERITED.lock().expect("inherited fd lock poisoned");
+    if let Some(session) = inherited.get(&fd) {
+        return Ok(Box::new(session.clone()));
+    }
+
+    // Check that the descriptor is an open socket before taking ownership of it
+    let is_socket = fs::metadata(format!("/dev/fd/{fd}")).is_ok_and(|m| m.file_type().is_socket());
+    if !is_socket {
+        return Err(AgentError::Other(
+            format!("File descriptor {fd} is not an open socket").into(),
+        ));
+    }
+    // SAFETY: the descriptor is open, and nothing else in this process uses it, because it's
+    // only ever taken once, here
+    let fd_owned = unsafe { OwnedFd::from_raw_fd(fd) };
+    let tcp_stream = TcpStream::from(fd_owned);
+    let stream = if tcp_stream.local_addr().is_ok() {
+        tcp_stream.into()
+    } else {
+        UnixStream::from(OwnedFd::from(tcp_stream)).into()
+    };
+    let client = client::connect(stream).map_err(|e| {
+        AgentError::Other(format!("Failed to connect to agent on fd:{}: {}", fd, e).into())
+    })?;
+    log::trace!("Connected to upstream agent on inherited fd:{}", fd);
+
+    let session = SharedSession(Arc::new(Mutex::new(client)));
+    inherited.insert(fd, session.clone());
+    Ok(Box::new(session))
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for SharedSession {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        self.0.lock().await.request_identities().await
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        self.0.lock().await.sign(request).await
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.0.lock().await.add_identity(identity).await
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        self.0.lock().await.add_identity_constrained(identity).await
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        self.0.lock().await.remove_identity(identity).await
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        self.0.lock().await.remove_all_identities().await
+    }
+
+    async fn add_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.0.lock().await.add_smartcard_key(key).await
+    }
+
+    async fn add_smartcard_key_constrained(
+        &mut self,
+        key: AddSmartcardKeyConstrained,
+    ) -> Result<(), AgentError> {
+        self.0.lock().await.add_smartcard_key_constrained(key).await
+    }
+
+    async fn remove_smartcard_key(&mut self, key: SmartcardKey) -> Result<(), AgentError> {
+        self.0.lock().await.remove_smartcard_key(key).await
+    }
+
+    async fn lock(&mut self, key: String) -> Result<(), AgentError> {
+        self.0.lock().await.lock(key).await
+    }
+
+    async fn unlock(&mut self, key: String) -> Result<(), AgentError> {
+        self.0.lock().await.unlock(key).await
+    }
+
+    async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
+        self.0.lock().await.extension(extension).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_addresses() {
+        for (s, expected) in [
+            (
+                "/run/agent.sock",
+                UpstreamAddress::Unix("/run/agent.sock".into()),
+            ),
+            (
+                "unix:/run/agent.sock",
+                UpstreamAddress::Unix("/run/agent.sock".into()),
+            ),
+            (
+                "unix-abstract:agent",
+                UpstreamAddress::UnixAbstract("agent".into()),
+            ),
+            (
+                "tcp:127.0.0.1:2222",
+                UpstreamAddress::Tcp("127.0.0.1:2222".into()),
+            ),
+            ("tcp:[::1]:2222", UpstreamAddress::Tcp("[::1]:2222".into())),
+            ("fd:3", UpstreamAddress::Fd(3)),
+        ] {
+            assert_eq!(s.parse::<UpstreamAddress>(), Ok(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_connect_times_out() {
+        // TEST-NET-1, which is never routed, so connecting hangs or fails at once
+        let started = std::time::Instant::now();
+        let result = connect_tcp("192.0.2.1:22", Duration::from_millis(200)).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn parse_invalid_addresses() {
+        for s in [
+            "unix:",
+            "unix-abstract:",
+            "tcp:localhost",
+            "tcp:",
+            "fd:",
+            "fd:-1",
+            "fd:x",
+        ] {
+            assert!(s.parse::<UpstreamAddress>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in [
+            "/run/agent.sock",
+            "unix-abstract:agent",
+            "tcp:127.0.0.1:2222",
+            "fd:3",
+        ] {
+            assert_eq!(s.parse::<UpstreamAddress>().unwrap().to_string(), s);
+        }
+    }
+}
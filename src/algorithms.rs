@@ -0,0 +1,66 @@
+//! Refusing weak signature algorithms and key types, whatever upstream agents would agree to
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::{
+    proto::signature::{RSA_SHA2_256, RSA_SHA2_512},
+    ssh_key::public::KeyData as PubKeyData,
+};
+
+/// Minimum acceptable signature algorithms and key types
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlgorithmPolicy {
+    /// What to do with requests for legacy `ssh-rsa` signatures, which hash with SHA-1
+    ///
+    /// They can't be passed on as they are, because `ssh-key` can't decode an `ssh-rsa`
+    /// signature from an upstream agent.
+    pub sha1_rsa: Sha1Rsa,
+
+    /// Key types, such as `ssh-ed25519`, or glob patterns of them, that may be used
+    ///
+    /// If empty, keys of any type not in `deny_key_types` may be used.
+    pub key_types: Vec<String>,
+
+    /// Key types, such as `ssh-dss`, or glob patterns of them, that may never be used
+    pub deny_key_types: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sha1Rsa {
+    /// Refuse the request
+    #[default]
+    Refuse,
+    /// Ask for an `rsa-sha2-512` signature instead
+    Upgrade,
+}
+
+impl AlgorithmPolicy {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Whether keys of `key`'s type may be listed and used
+    pub(crate) fn allows_key(&self, key: &PubKeyData) -> bool {
+        let key_type = key.algorithm();
+        let matches = |pattern: &String| {
+            glob::Pattern::new(pattern).is_ok_and(|p| p.matches(key_type.as_str()))
+        };
+        (self.key_types.is_empty() || self.key_types.iter().any(matches))
+            && !self.deny_key_types.iter().any(matches)
+    }
+
+    /// The signature flags to forward a request to sign with `key` with, or `None` if the request
+    /// must be refused
+    pub(crate) fn sign_flags(&self, key: &PubKeyData, flags: u32) -> Option<u32> {
+        let is_sha1_rsa =
+            matches!(key, PubKeyData::Rsa(_)) && flags & (RSA_SHA2_256 | RSA_SHA2_512) == 0;
+        if !is_sha1_rsa {
+            return Some(flags);
+        }
+        match self.sha1_rsa {
+            Sha1Rsa::Refuse => None,
+            Sha1Rsa::Upgrade => Some(flags | RSA_SHA2_512),
+        }
+    }
+}
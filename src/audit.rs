@@ -0,0 +1,159 @@
+//! Append-only JSON-lines log of every signing request, separate from diagnostic logging
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use ssh_agent_lib::{error::AgentError, ssh_key::public::KeyData as PubKeyData};
+
+use crate::{clients::ClientProcess, purpose::Purpose};
+
+/// Audit log file, shared by every session
+#[derive(Clone, Debug)]
+pub(crate) struct AuditLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    pub fn write(&self, record: &SignRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        // A single write, so that records are never interleaved
+        let mut file = self.file.lock().expect("audit log lock poisoned");
+        if let Err(e) = file.write_all(&line) {
+            log::error!("Failed to write audit record: {}", e);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Outcome {
+    Signed,
+    /// Refused by the mux's own policies
+    Refused,
+    /// Failed upstream, or because no upstream holds the key
+    #[default]
+    Failed,
+}
+
+/// One signing request
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct SignRecord {
+    /// RFC 3339, in UTC
+    pub timestamp: String,
+    pub fingerprint: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    pub outcome: Outcome,
+    /// Why the request was refused, or the error it failed with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub latency_ms: f64,
+    pub client: ClientRecord,
+    /// `userauth`, `sshsig`, or `unknown`
+    pub purpose: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// Fingerprint of the server's host key, from the verified `session-bind@openssh.com`
+    /// extension, or else from user authentication data
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub(crate) struct ClientRecord {
+    pub pid: Option<i32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub exe: Option<String>,
+}
+
+impl SignRecord {
+    pub fn new(
+        purpose: &Purpose,
+        client: &ClientProcess,
+        bound_host_key: Option<&PubKeyData>,
+    ) -> Self {
+        let fingerprint = |key: &PubKeyData| key.fingerprint(Default::default()).to_string();
+        let mut record = Self {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            client: ClientRecord {
+                pid: client.pid(),
+                uid: client.credentials().map(|c| c.uid),
+                gid: client.credentials().map(|c| c.gid),
+                exe: client.exe().map(|exe| exe.display().to_string()),
+            },
+            host_key: bound_host_key.map(fingerprint),
+            ..Default::default()
+        };
+        match purpose {
+            Purpose::UserAuth {
+                user,
+                service,
+                host_key,
+            } => {
+                record.purpose = "userauth";
+                record.user = Some(user.clone());
+                record.service = Some(service.clone());
+                // The client chooses what it asks to sign, so prefer the verified binding
+                if record.host_key.is_none() {
+                    record.host_key = host_key.as_ref().map(fingerprint);
+                }
+            }
+            Purpose::Sshsig { namespace } => {
+                record.purpose = "sshsig";
+                record.namespace = Some(namespace.clone());
+            }
+            Purpose::Unknown => record.purpose = "unknown",
+        }
+        record
+    }
+
+    /// Mark the request refused by a policy of the mux, before it's sent to any upstream
+    pub fn refuse(&mut self, reason: &str) {
+        self.outcome = Outcome::Refused;
+        self.reason = Some(reason.into());
+    }
+
+    pub fn finish<T>(&mut self, result: &Result<T, AgentError>, latency: Duration) {
+        self.latency_ms = latency.as_secs_f64() * 1000.0;
+        match result {
+            Ok(_) => self.outcome = Outcome::Signed,
+            Err(_) if self.outcome == Outcome::Refused => (),
+            Err(e) => {
+                self.outcome = Outcome::Failed;
+                self.reason = Some(e.to_string());
+            }
+        }
+    }
+}
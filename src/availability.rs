@@ -0,0 +1,214 @@
+//! Windows of time in which keys are available, so that powerful keys aren't offered around the
+//! clock
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use chrono::{Datelike, NaiveDateTime, Weekday};
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::ssh_key::public::KeyData as PubKeyData;
+
+use crate::{clients, rules::Hours};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<Weekday> for Day {
+    fn from(day: Weekday) -> Self {
+        match day {
+            Weekday::Mon => Self::Mon,
+            Weekday::Tue => Self::Tue,
+            Weekday::Wed => Self::Wed,
+            Weekday::Thu => Self::Thu,
+            Weekday::Fri => Self::Fri,
+            Weekday::Sat => Self::Sat,
+            Weekday::Sun => Self::Sun,
+        }
+    }
+}
+
+/// When some keys are available; outside of it they're hidden, and can't sign
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Availability {
+    /// Fingerprints (`SHA256:...`) or public keys of the keys this window applies to
+    pub keys: Vec<String>,
+
+    /// Socket paths or glob patterns of upstream agents whose keys this window applies to
+    ///
+    /// With neither `keys` nor `upstreams`, the window applies to every key.
+    pub upstreams: Vec<PathBuf>,
+
+    /// Days of the week, in local time, that the keys are available on
+    ///
+    /// If empty, the keys are available every day.
+    pub days: Vec<Day>,
+
+    /// Local times of day that the keys are available at, such as `08:00-20:00`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hours: Option<Hours>,
+
+    /// How long the keys are available for after they appear from their upstream agent, such as
+    /// when it's unlocked, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unlocked_lifetime: Option<u64>,
+}
+
+impl Availability {
+    fn applies(&self, key: &PubKeyData, upstream: &str) -> bool {
+        let matches_upstream = |pattern: &PathBuf| {
+            glob::Pattern::new(&pattern.to_string_lossy()).is_ok_and(|p| p.matches(upstream))
+        };
+        (self.keys.is_empty() && self.upstreams.is_empty())
+            || clients::key_listed(&self.keys, key)
+            || self.upstreams.iter().any(matches_upstream)
+    }
+
+    fn allows(&self, local_time: NaiveDateTime, available_for: Duration) -> bool {
+        (self.days.is_empty() || self.days.contains(&local_time.weekday().into()))
+            && self.hours.is_none_or(|h| h.contains(local_time.time()))
+            && self
+                .unlocked_lifetime
+                .is_none_or(|secs| available_for < Duration::from_secs(secs))
+    }
+}
+
+/// Whether `key`, held by `upstream` since `since`, is available now, by every window that
+/// applies to it
+pub(crate) fn is_available(
+    windows: &[Availability],
+    key: &PubKeyData,
+    upstream: &str,
+    since: Instant,
+) -> bool {
+    let local_time = chrono::Local::now().naive_local();
+    let available_for = since.elapsed();
+    windows
+        .iter()
+        .filter(|w| w.applies(key, upstream))
+        .all(|w| w.allows(local_time, available_for))
+}
+
+/// When each key appeared from each upstream agent, for `unlocked_lifetime`
+///
+/// A key's time only starts again once its upstream lists keys without it, not when the upstream
+/// isn't asked, such as while it's failing or disabled.
+#[derive(Debug, Default)]
+pub(crate) struct KeyAppearances {
+    since: HashMap<(String, PubKeyData), Instant>,
+}
+
+impl KeyAppearances {
+    /// Record that `upstream` lists `keys`, and only them
+    pub fn listed<'a>(
+        &mut self,
+        upstream: &str,
+        keys: impl IntoIterator<Item = &'a PubKeyData>,
+        now: Instant,
+    ) {
+        let keys: HashSet<_> = keys.into_iter().collect();
+        self.since
+            .retain(|(name, key), _| name != upstream || keys.contains(key));
+        for key in keys {
+            self.since
+                .entry((upstream.into(), key.clone()))
+                .or_insert(now);
+        }
+    }
+
+    pub fn since(&self, upstream: &str, key: &PubKeyData) -> Option<Instant> {
+        self.since.get(&(upstream.into(), key.clone())).copied()
+    }
+
+    /// Forget upstreams other than `upstreams`, which are no longer configured
+    pub fn retain(&mut self, upstreams: &[String]) {
+        self.since.retain(|(name, _), _| upstreams.contains(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_agent_lib::ssh_key::public::Ed25519PublicKey;
+
+    use super::*;
+
+    #[test]
+    fn windows_by_day_hours_and_lifetime() {
+        let weekdays = Availability {
+            days: vec![Day::Mon, Day::Tue, Day::Wed, Day::Thu, Day::Fri],
+            hours: Some("08:00-20:00".to_string().try_into().unwrap()),
+            ..Default::default()
+        };
+        let at = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        // 2026-10-19 is a Monday
+        assert!(weekdays.allows(at("2026-10-19 09:00"), Duration::ZERO));
+        assert!(!weekdays.allows(at("2026-10-19 21:00"), Duration::ZERO));
+        assert!(!weekdays.allows(at("2026-10-18 09:00"), Duration::ZERO));
+
+        let for_an_hour = Availability {
+            unlocked_lifetime: Some(3600),
+            ..Default::default()
+        };
+        let now = at("2026-10-19 09:00");
+        assert!(for_an_hour.allows(now, Duration::from_secs(3599)));
+        assert!(!for_an_hour.allows(now, Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn windows_apply_by_key_or_upstream() {
+        let key: PubKeyData = Ed25519PublicKey([1; 32]).into();
+        let fingerprint = key.fingerprint(Default::default()).to_string();
+        let by_key = Availability {
+            keys: vec![fingerprint],
+            ..Default::default()
+        };
+        let by_upstream = Availability {
+            upstreams: vec!["/run/user/*/prod.sock".into()],
+            ..Default::default()
+        };
+        let other_key: PubKeyData = Ed25519PublicKey([2; 32]).into();
+        assert!(by_key.applies(&key, "/tmp/agent.sock"));
+        assert!(!by_key.applies(&other_key, "/tmp/agent.sock"));
+        assert!(by_upstream.applies(&other_key, "/run/user/1000/prod.sock"));
+        assert!(!by_upstream.applies(&other_key, "/tmp/agent.sock"));
+        assert!(Availability::default().applies(&other_key, "/tmp/agent.sock"));
+    }
+
+    #[test]
+    fn appearances_kept_until_keys_are_gone() {
+        let key: PubKeyData = Ed25519PublicKey([1; 32]).into();
+        let other_key: PubKeyData = Ed25519PublicKey([2; 32]).into();
+        let mut appearances = KeyAppearances::default();
+        let start = Instant::now();
+        let later = start + Duration::from_secs(60);
+
+        appearances.listed("/tmp/agent.sock", [&key, &other_key], start);
+        // Listing other upstreams, or none, changes nothing
+        appearances.listed("/tmp/other.sock", [], later);
+        appearances.listed("/tmp/agent.sock", [&key, &other_key], later);
+        assert_eq!(appearances.since("/tmp/agent.sock", &key), Some(start));
+
+        appearances.listed("/tmp/agent.sock", [&key], later);
+        appearances.listed("/tmp/agent.sock", [&key, &other_key], later);
+        assert_eq!(appearances.since("/tmp/agent.sock", &key), Some(start));
+        assert_eq!(
+            appearances.since("/tmp/agent.sock", &other_key),
+            Some(later)
+        );
+
+        appearances.retain(&[]);
+        assert_eq!(appearances.since("/tmp/agent.sock", &key), None);
+    }
+}
@@ -6,15 +6,22 @@ use std::{
     path::{Path, PathBuf},
 };
 
+use chrono::NaiveTime;
 use clap_serde_derive::{
     clap::{self, Parser, ValueEnum},
     serde::{self, Deserialize, Serialize},
     ClapSerde,
 };
-use color_eyre::eyre::Result as EyreResult;
+use color_eyre::eyre::{eyre, Result as EyreResult};
 use log::LevelFilter;
+use ssh_agent_lib::ssh_key::PublicKey;
+use ssh_agent_mux::{
+    AlgorithmPolicy, AllowedClients, Availability, CertificateConfig, ClientPolicy, Hook,
+    LockConfig, MuxOptions, PurposePolicy, RateLimits, Rule, RuleRequest, UpstreamAddress,
+    UpstreamConfig,
+};
 
-use crate::service;
+use crate::{inspect, service};
 
 fn default_config_path() -> PathBuf {
     let config_dir = env::var_os("XDG_CONFIG_HOME")
@@ -32,6 +39,61 @@ fn expand_path(path: impl AsRef<Path>) -> EyreResult<PathBuf> {
         .map_err(|e| e.into())
 }
 
+fn expand_command(command: Vec<String>) -> EyreResult<Vec<String>> {
+    command
+        .iter()
+        .map(|arg| Ok(shellexpand::full(arg)?.into_owned()))
+        .collect()
+}
+
+/// Hooks get their details in environment variables, so leave those for the hook's shell
+fn expand_hook(command: Vec<String>) -> Vec<String> {
+    command
+        .iter()
+        .map(|arg| shellexpand::tilde(arg).into_owned())
+        .collect()
+}
+
+fn expand_upstream(upstream: UpstreamConfig) -> EyreResult<UpstreamConfig> {
+    Ok(match upstream {
+        UpstreamConfig::Address(UpstreamAddress::Unix(p)) => {
+            UpstreamConfig::Address(UpstreamAddress::Unix(expand_path(p)?))
+        }
+        UpstreamConfig::Unverified {
+            unverified: UpstreamAddress::Unix(p),
+        } => UpstreamConfig::Unverified {
+            unverified: UpstreamAddress::Unix(expand_path(p)?),
+        },
+        UpstreamConfig::KeyFiles { mut key_files } => {
+            key_files.paths = key_files
+                .paths
+                .into_iter()
+                .map(expand_path)
+                .collect::<Result<_, _>>()?;
+            key_files.askpass = key_files.askpass.map(expand_path).transpose()?;
+            UpstreamConfig::KeyFiles { key_files }
+        }
+        UpstreamConfig::Exec { exec } => UpstreamConfig::Exec {
+            exec: expand_command(exec)?,
+        },
+        UpstreamConfig::PathCommand { path_command } => UpstreamConfig::PathCommand {
+            path_command: expand_command(path_command)?,
+        },
+        UpstreamConfig::Glob { glob } => UpstreamConfig::Glob {
+            glob: expand_path(glob)?,
+        },
+        UpstreamConfig::Group { mut group } => {
+            group.sockets = group
+                .sockets
+                .into_iter()
+                .map(expand_path)
+                .collect::<Result<_, _>>()?;
+            UpstreamConfig::Group { group }
+        }
+        other => other,
+    })
+}
+
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Args {
@@ -39,9 +101,22 @@ struct Args {
     #[arg(short, long = "config", default_value_os_t = default_config_path())]
     config_path: PathBuf,
 
+    /// Show which rule decides a hypothetical request, then exit
+    ///
+    /// The request is described by FIELD=VALUE arguments: `key` (a fingerprint or public key
+    /// file), `upstream`, `exe` (repeated for the client and each process that started it),
+    /// `uid`, `host_key` (a fingerprint or public key file), `forwarded` (`true` or `false`),
+    /// `purpose` (`userauth`, `sshsig` or `unknown`; listing keys if not given), `user`,
+    /// `namespace`, and `time` (`HH:MM`, today).
+    #[arg(long, value_name = "FIELD=VALUE", num_args = 0..)]
+    explain: Option<Vec<String>>,
+
     /// Config from file or args
     #[command(flatten)]
     config: <Config as ClapSerde>::Opt,
+
+    #[command(subcommand)]
+    command: Option<inspect::Command>,
 }
 
 #[derive(ClapSerde, Clone, Serialize)]
@@ -63,16 +138,99 @@ pub struct Config {
     #[arg(long, num_args = 1)]
     pub log_file: Option<PathBuf>,
 
+    /// Optional audit log file, with a JSON record of each signing request appended to it
+    #[arg(long, num_args = 1)]
+    pub audit_log: Option<PathBuf>,
+
     /// Agent sockets to multiplex
     ///
-    /// Must be specified as absolute paths. Any of the paths can contain a shell-style reference
-    /// to an environment variable or start with "~" for the home directory.
+    /// Must be specified as absolute paths, or as addresses such as `unix-abstract:name`,
+    /// `tcp:host:port`, or `fd:N`. Any of the paths can contain a shell-style reference to an
+    /// environment variable or start with "~" for the home directory.
     ///
     /// The order affects the order in which public keys are offered to an SSH server. If keys from
     /// multiple agents are listed on an SSH server in your `authorized_keys` file, the agent listed
     /// first here will be the one selected to authenticate with the server.
+    ///
+    /// In the configuration file, `{ keystore = {} }` adds a built-in keystore that holds keys
+    /// added with `ssh-add`, and `{ key_files = { paths = ["~/.ssh/id_*"] } }` signs with private
+    /// key files directly. `{ exec = ["command", "arg"] }` runs a command that speaks the agent
+    /// protocol on its standard input and output, and `{ path_command = ["command", "arg"] }` uses
+    /// the socket path printed by a command. `{ glob = "/tmp/ssh-*/agent.*" }` matches any number
+    /// of agent sockets, owned by the current user, each time keys are listed, and
+    /// `{ group = { sockets = [...], strategy = "newest" } }` chooses one of several candidates.
+    ///
+    /// Unix sockets are only connected to if they, and the directories containing them, are owned
+    /// by the current user or root. `{ unverified = "/path" }` trusts a socket regardless.
     #[arg()]
-    pub agent_sock_paths: Vec<PathBuf>,
+    pub agent_sock_paths: Vec<UpstreamConfig>,
+
+    /// Filtering of OpenSSH certificates offered by upstream agents (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "CertificateConfig::is_empty")]
+    pub certificates: CertificateConfig,
+
+    /// Also check that upstream agents run as the current user, using the credentials of their
+    /// socket connection (configuration file only)
+    #[arg(skip)]
+    pub verify_peer_credentials: bool,
+
+    /// Other users and groups allowed to connect, besides the user running the mux
+    /// (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "AllowedClients::is_empty")]
+    pub allowed_clients: AllowedClients,
+
+    /// Keys that each client program may use, by its executable (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_policies: Vec<ClientPolicy>,
+
+    /// What each key may be used to sign, such as only git commits (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub purpose_policies: Vec<PurposePolicy>,
+
+    /// Minimum acceptable signature algorithms and key types (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "AlgorithmPolicy::is_empty")]
+    pub algorithms: AlgorithmPolicy,
+
+    /// Limits on how fast clients may ask for signatures, per client and per key
+    /// (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "RateLimits::is_empty")]
+    pub rate_limits: RateLimits,
+
+    /// Commands to run when something happens, such as a key being asked to sign
+    /// (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<Hook>,
+
+    /// Ordered rules deciding which keys are offered to clients, and what they may sign
+    /// (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+
+    /// Windows of time in which keys are available, such as only on weekdays
+    /// (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub availability: Vec<Availability>,
+
+    /// When to lock automatically, hiding keys until unlocked with `ssh-add -X`
+    /// (configuration file only)
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "LockConfig::is_empty")]
+    pub lock: LockConfig,
+
+    /// Listen on a control socket, the listen path with a `.ctl` extension, for administrative
+    /// requests such as listing keys or reloading the configuration (configuration file only)
+    #[default(true)]
+    #[arg(skip)]
+    pub control_socket: bool,
 
     // Following are part of command line args, but
     // not in configuration file
@@ -84,6 +242,16 @@ pub struct Config {
     #[serde(skip)]
     #[command(flatten)]
     pub service: service::ServiceArgs,
+
+    /// Hypothetical request to explain (not an arg; copied from struct Args)
+    #[arg(skip)]
+    #[serde(skip)]
+    pub explain: Option<Vec<String>>,
+
+    /// Subcommand inspecting the running mux (not an arg; copied from struct Args)
+    #[arg(skip)]
+    #[serde(skip)]
+    pub command: Option<inspect::Command>,
 }
 
 impl Config {
@@ -102,20 +270,115 @@ impl Config {
         };
 
         config.config_path = args.config_path;
+        config.explain = args.explain;
+        config.command = args.command;
         config.listen_path = expand_path(config.listen_path)?;
         config.log_file = config
             .log_file
             .filter(|p| p != OsStr::new("-"))
             .map(expand_path)
             .transpose()?;
+        config.audit_log = config.audit_log.map(expand_path).transpose()?;
         config.agent_sock_paths = config
             .agent_sock_paths
             .into_iter()
+            .map(expand_upstream)
+            .collect::<Result<_, _>>()?;
+        for policy in &mut config.client_policies {
+            policy.programs = expand_command(std::mem::take(&mut policy.programs))?;
+            for path in policy
+                .upstreams
+                .iter_mut()
+                .chain(&mut policy.deny_upstreams)
+            {
+                *path = expand_path(&path)?;
+            }
+        }
+        config.rate_limits.on_limit = expand_hook(std::mem::take(&mut config.rate_limits.on_limit));
+        for rule in &mut config.rules {
+            rule.programs = expand_command(std::mem::take(&mut rule.programs))?;
+            for path in &mut rule.upstreams {
+                *path = expand_path(&path)?;
+            }
+        }
+        for window in &mut config.availability {
+            for path in &mut window.upstreams {
+                *path = expand_path(&path)?;
+            }
+        }
+        for hook in &mut config.hooks {
+            hook.command = expand_hook(std::mem::take(&mut hook.command));
+        }
+        config.certificates.paths = config
+            .certificates
+            .paths
+            .into_iter()
             .map(expand_path)
             .collect::<Result<_, _>>()?;
 
         Ok(config)
     }
+
+    pub fn mux_options(&self) -> MuxOptions {
+        MuxOptions {
+            certificates: self.certificates.clone(),
+            verify_peer_credentials: self.verify_peer_credentials,
+            allowed_clients: self.allowed_clients.clone(),
+            client_policies: self.client_policies.clone(),
+            purpose_policies: self.purpose_policies.clone(),
+            algorithms: self.algorithms.clone(),
+            audit_log: self.audit_log.clone(),
+            rate_limits: self.rate_limits.clone(),
+            hooks: self.hooks.clone(),
+            rules: self.rules.clone(),
+            availability: self.availability.clone(),
+            lock: self.lock.clone(),
+            control_socket: self.control_socket,
+            control_events: None,
+            state: Default::default(),
+        }
+    }
+}
+
+/// A fingerprint, or the fingerprint of the key in a public key file
+pub fn fingerprint(value: &str) -> EyreResult<String> {
+    if value.starts_with("SHA256:") {
+        return Ok(value.into());
+    }
+    let key = PublicKey::read_openssh_file(&expand_path(value)?)?;
+    Ok(key.fingerprint(Default::default()).to_string())
+}
+
+impl Config {
+    /// The hypothetical request described by `--explain`
+    pub fn explain_request(&self) -> EyreResult<Option<RuleRequest>> {
+        let Some(fields) = &self.explain else {
+            return Ok(None);
+        };
+        let mut request = RuleRequest::default();
+        for field in fields {
+            let (name, value) = field
+                .split_once('=')
+                .ok_or_else(|| eyre!("Expected FIELD=VALUE, not {:?}", field))?;
+            match name {
+                "key" => request.key = Some(fingerprint(value)?),
+                "upstream" => request.upstream = Some(expand_path(value)?.display().to_string()),
+                "exe" => request.programs.push(expand_path(value)?),
+                "uid" => request.uid = Some(value.parse()?),
+                "host_key" => request.host_key = Some(fingerprint(value)?),
+                "forwarded" => request.forwarded = value.parse()?,
+                "purpose" => request.purpose = Some(value.parse().map_err(|e: String| eyre!(e))?),
+                "user" => request.user = Some(value.into()),
+                "namespace" => request.namespace = Some(value.into()),
+                "time" => {
+                    let time = NaiveTime::parse_from_str(value, "%H:%M")?;
+                    request.time = request.time.date().and_time(time);
+                }
+                _ => return Err(eyre!("Unknown field {:?} in {:?}", name, field)),
+            }
+        }
+        Ok(Some(request))
+    }
 }
 
 #[derive(ValueEnum, Clone, Copy, Deserialize, Serialize)]
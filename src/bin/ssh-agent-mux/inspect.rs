@@ -0,0 +1,270 @@
+use std::{
+    env,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    os::fd::AsRawFd,
+    path::Path,
+    process::{Command as Process, Stdio},
+};
+
+use clap_serde_derive::clap::{self, Subcommand};
+use color_eyre::{
+    eyre::{bail, Result, WrapErr},
+    Section,
+};
+use serde::Serialize;
+use ssh_agent_mux::{
+    control_path, control_request, ControlRequest, ControlResponse, KeyStatus, Status,
+    UpstreamStatus,
+};
+
+use crate::cli::{self, Config};
+
+/// Ask the running mux about its upstream agents and keys, through its control socket
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Show whether the running mux is locked, and the health of its upstream agents
+    Status {
+        #[arg(long)]
+        json: bool,
+    },
+    /// List keys, with the upstream agent offering each and its health
+    Keys {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show which upstream agent offers a key
+    Which {
+        /// Fingerprint (`SHA256:...`) or public key file
+        key: String,
+        #[arg(long)]
+        json: bool,
+    },
+    /// List keys from upstream agents again, retrying failing ones immediately
+    Refresh {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Lock the running mux with a passphrase, as `ssh-add -x` does
+    ///
+    /// The passphrase is read from the terminal, or with the `SSH_ASKPASS` program if there's no
+    /// terminal or `SSH_ASKPASS_REQUIRE=force`.
+    Lock,
+    /// Unlock the running mux, as `ssh-add -X` does
+    ///
+    /// The passphrase is read as for `lock`.
+    Unlock,
+}
+
+#[derive(Serialize)]
+struct StatusOutput {
+    status: Status,
+    upstreams: Vec<UpstreamStatus>,
+}
+
+/// A key, with the health of the upstream agent offering it
+#[derive(Serialize)]
+struct KeyOutput {
+    #[serde(flatten)]
+    key: KeyStatus,
+    health: String,
+}
+
+pub fn handle_command(config: &Config, command: &Command) -> Result<()> {
+    let socket = control_path(&config.listen_path);
+    match command {
+        Command::Status { json } => {
+            let Some(status) = request(&socket, ControlRequest::Status)?.status else {
+                bail!("No status in response from {}", socket.display());
+            };
+            let upstreams = upstreams(&socket)?;
+            if *json {
+                return print_json(&StatusOutput { status, upstreams });
+            }
+            println!(
+                "{} {}, listening on {}",
+                env!("CARGO_PKG_NAME"),
+                status.version,
+                status.listen_path.display()
+            );
+            println!(
+                "{}; {} of {} upstream agents healthy; {} keys",
+                if status.locked { "Locked" } else { "Unlocked" },
+                status.healthy_upstreams,
+                status.upstreams,
+                status.keys
+            );
+            for upstream in &upstreams {
+                println!(
+                    "  {}: {}, {} keys",
+                    upstream.name,
+                    health(upstream),
+                    upstream.keys
+                );
+            }
+        }
+        Command::Keys { json } => print_keys(&socket, ControlRequest::Keys, *json)?,
+        Command::Refresh { json } => print_keys(&socket, ControlRequest::Refresh, *json)?,
+        Command::Which { key, json } => {
+            let fingerprint = cli::fingerprint(key)?;
+            let find =
+                |keys: Vec<KeyOutput>| keys.into_iter().find(|k| k.key.fingerprint == fingerprint);
+            // Keys are only listed from upstream agents when clients ask, so refresh if needed
+            let found = match find(keys(&socket, ControlRequest::Keys)?) {
+                Some(found) => found,
+                None => match find(keys(&socket, ControlRequest::Refresh)?) {
+                    Some(found) => found,
+                    None => bail!("No upstream agent offers key {}", fingerprint),
+                },
+            };
+            if *json {
+                return print_json(&found);
+            }
+            println!("{} ({})", found.key.upstream, found.health);
+        }
+        Command::Lock => {
+            let passphrase = read_passphrase("Enter lock password: ")?;
+            if read_passphrase("Again: ")? != passphrase {
+                bail!("Passwords do not match");
+            }
+            request(&socket, ControlRequest::Lock { passphrase })?;
+            println!("Locked");
+        }
+        Command::Unlock => {
+            let passphrase = read_passphrase("Enter lock password: ")?;
+            request(&socket, ControlRequest::Unlock { passphrase })?;
+            println!("Unlocked");
+        }
+    }
+    Ok(())
+}
+
+fn request(socket: &Path, request: ControlRequest) -> Result<ControlResponse> {
+    let response = control_request(socket, &request)
+        .wrap_err_with(|| format!("Failed to connect to {}", socket.display()))
+        .note(concat!(
+            "Is ",
+            env!("CARGO_PKG_NAME"),
+            " running, with control_socket enabled?"
+        ))?;
+    if !response.ok {
+        bail!("{}", response.error.as_deref().unwrap_or("request failed"));
+    }
+    Ok(response)
+}
+
+fn upstreams(socket: &Path) -> Result<Vec<UpstreamStatus>> {
+    Ok(request(socket, ControlRequest::Upstreams)?
+        .upstreams
+        .unwrap_or_default())
+}
+
+/// Keys from `request`, which responds with keys, with the health of their upstream agents
+fn keys(socket: &Path, request_keys: ControlRequest) -> Result<Vec<KeyOutput>> {
+    let keys = request(socket, request_keys)?.keys.unwrap_or_default();
+    let upstreams = upstreams(socket)?;
+    Ok(keys
+        .into_iter()
+        .map(|key| {
+            let health = upstreams
+                .iter()
+                .find(|u| u.name == key.upstream)
+                .map_or_else(|| "unknown".into(), health);
+            KeyOutput { key, health }
+        })
+        .collect())
+}
+
+fn print_keys(socket: &Path, request_keys: ControlRequest, json: bool) -> Result<()> {
+    let keys = keys(socket, request_keys)?;
+    if json {
+        return print_json(&keys);
+    }
+    if keys.is_empty() {
+        println!("No keys");
+    }
+    for KeyOutput { key, health } in &keys {
+        println!(
+            "{} {} ({})\n  from {} ({})",
+            key.fingerprint, key.comment, key.algorithm, key.upstream, health
+        );
+    }
+    Ok(())
+}
+
+/// Short description of an upstream agent's health
+fn health(upstream: &UpstreamStatus) -> String {
+    if !upstream.enabled {
+        return "disabled".into();
+    }
+    if upstream.healthy {
+        return "healthy".into();
+    }
+    let mut health = format!("{} consecutive failures", upstream.consecutive_failures);
+    if let Some(error) = &upstream.last_error {
+        health.push_str(&format!(", last error: {error}"));
+    }
+    if let Some(retry_in_ms) = upstream.retry_in_ms {
+        health.push_str(&format!(
+            ", retrying in {:.1}s",
+            retry_in_ms as f64 / 1000.0
+        ));
+    }
+    health
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// Read a passphrase like `ssh-add` does: from the terminal without echoing it, or with the
+/// `SSH_ASKPASS` program if there's no terminal or `SSH_ASKPASS_REQUIRE` is `force`
+fn read_passphrase(prompt: &str) -> Result<String> {
+    let require = env::var("SSH_ASKPASS_REQUIRE").unwrap_or_default();
+    if require != "force" {
+        if let Ok(tty) = File::options().read(true).write(true).open("/dev/tty") {
+            return read_tty_passphrase(tty, prompt);
+        }
+        if require == "never" {
+            bail!("No terminal to read the passphrase from");
+        }
+    }
+
+    let program = env::var_os("SSH_ASKPASS").unwrap_or_else(|| "ssh-askpass".into());
+    let output = Process::new(&program)
+        .arg(prompt)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .wrap_err_with(|| format!("Failed to run {}", Path::new(&program).display()))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed: {}",
+            Path::new(&program).display(),
+            output.status
+        );
+    }
+    let passphrase = String::from_utf8(output.stdout)?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn read_tty_passphrase(mut tty: File, prompt: &str) -> Result<String> {
+    tty.write_all(prompt.as_bytes())?;
+    let fd = tty.as_raw_fd();
+    // SAFETY: termios is plain data, filled in by tcgetattr before it's used
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    let echo_off = unsafe { libc::tcgetattr(fd, &mut termios) } == 0 && {
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &silent) == 0 }
+    };
+    let mut passphrase = String::new();
+    let read = BufReader::new(&tty).read_line(&mut passphrase);
+    if echo_off {
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &termios) };
+    }
+    tty.write_all(b"\n")?;
+    read?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -1,9 +1,11 @@
 use color_eyre::eyre::Result as EyreResult;
-use ssh_agent_mux::MuxAgent;
+use ssh_agent_mux::{ControlEvent, MuxAgent, MuxOptions, MuxState};
 use tokio::select;
 use tokio::signal::{self, unix::SignalKind};
+use tokio::sync::mpsc;
 
 mod cli;
+mod inspect;
 mod logging;
 mod service;
 
@@ -31,27 +33,61 @@ async fn main() -> EyreResult<()> {
     let mut config = cli::Config::parse()?;
 
     // LoggerHandle must be held until program termination so file logging takes place
-    let _logger = logging::setup_logger(config.log_level.into(), config.log_file.as_deref())?;
+    let logger = logging::setup_logger(config.log_level.into(), config.log_file.as_deref())?;
+
+    if let Some(command) = &config.command {
+        return inspect::handle_command(&config, command);
+    }
 
     if config.service.any() {
         return service::handle_service_command(&config);
     }
 
+    if let Some(request) = config.explain_request()? {
+        println!("{}", ssh_agent_mux::explain(&config.rules, &request));
+        return Ok(());
+    }
+
     let mut sigterm = signal::unix::signal(SignalKind::terminate())?;
     let mut sighup = signal::unix::signal(SignalKind::hangup())?;
 
+    let (control_events, mut control_rx) = mpsc::unbounded_channel();
+    // Shared by every mux run, so that reloading doesn't unlock a locked mux
+    let state = MuxState::default();
+
     loop {
-        select! {
-            res = MuxAgent::run(&config.listen_path, &config.agent_sock_paths) => { res?; break },
-            // Cleanly exit on interrupt and SIGTERM, allowing
-            // MuxAgent to clean up
-            _ = signal::ctrl_c() => { log::info!("Exiting on SIGINT"); break },
-            Some(_) = sigterm.recv() => { log::info!("Exiting on SIGTERM"); break },
-            Some(_) = sighup.recv() => {
-                log::info!("Reloading configuration");
-                config = cli::Config::parse()?;
+        let options = MuxOptions {
+            control_events: Some(control_events.clone()),
+            state: state.clone(),
+            ..config.mux_options()
+        };
+        let mux = MuxAgent::run(
+            config.listen_path.clone(),
+            config.agent_sock_paths.clone(),
+            options,
+        );
+        tokio::pin!(mux);
+        // Keep running the same mux until it's reloaded, so that it keeps its state when the log
+        // level changes
+        let reload = loop {
+            select! {
+                res = &mut mux => { res?; break false },
+                // Cleanly exit on interrupt and SIGTERM, allowing
+                // MuxAgent to clean up
+                _ = signal::ctrl_c() => { log::info!("Exiting on SIGINT"); break false },
+                Some(_) = sigterm.recv() => { log::info!("Exiting on SIGTERM"); break false },
+                Some(_) = sighup.recv() => break true,
+                Some(event) = control_rx.recv() => match event {
+                    ControlEvent::Reload => break true,
+                    ControlEvent::LogLevel(level) => logging::set_level(&logger, level),
+                },
             }
+        };
+        if !reload {
+            break;
         }
+        log::info!("Reloading configuration");
+        config = cli::Config::parse()?;
     }
 
     Ok(())
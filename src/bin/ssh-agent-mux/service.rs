@@ -128,7 +128,7 @@ fn write_new_config_file(config: &Config) -> Result<()> {
             }
             Ok(v) => {
                 success_msg.write_str("with the current SSH_AUTH_SOCK as the upstream agent; please edit to add additional agents.")?;
-                new_config.agent_sock_paths.push(v.into());
+                new_config.agent_sock_paths.push(PathBuf::from(v).into());
             }
             Err(e) => {
                 match e {
@@ -0,0 +1,193 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::ssh_key::{public::KeyData as PubKeyData, Certificate};
+
+/// Suffix of certificate files picked up from directories listed in [`CertificateConfig::paths`]
+const CERT_FILE_SUFFIX: &str = "-cert.pub";
+
+/// Settings for OpenSSH certificates offered by upstream agents
+///
+/// Certificates outside of their validity window are always dropped, because an SSH server would
+/// reject them anyway.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CertificateConfig {
+    /// Only offer certificates that are valid for at least one of these principals
+    ///
+    /// If empty, certificates are offered regardless of their principals. Certificates that
+    /// don't list any principals are valid for every principal, so they are always offered.
+    pub principals: Vec<String>,
+
+    /// Certificate files, or directories containing `*-cert.pub` files, to attach to keys held
+    /// by upstream agents
+    ///
+    /// Each certificate is matched to an upstream key by its public key. Signing requests for an
+    /// attached certificate are sent to the upstream agent holding the underlying key.
+    pub paths: Vec<PathBuf>,
+
+    /// Whether attached certificates are offered alongside or instead of their plain keys
+    pub attach: AttachMode,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachMode {
+    /// Offer the certificate immediately after the plain key
+    #[default]
+    Alongside,
+    /// Offer only the certificate, hiding the plain key it was issued for
+    Instead,
+}
+
+impl CertificateConfig {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Check whether `cert` should be offered to clients at time `now`
+    pub(crate) fn check(&self, cert: &Certificate, now: SystemTime) -> Result<(), Rejection> {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if now < cert.valid_after() {
+            return Err(Rejection::NotYetValid);
+        }
+        if now >= cert.valid_before() {
+            return Err(Rejection::Expired);
+        }
+
+        let cert_principals = cert.valid_principals();
+        if !self.principals.is_empty()
+            && !cert_principals.is_empty()
+            && !cert_principals.iter().any(|p| self.principals.contains(p))
+        {
+            return Err(Rejection::Principals);
+        }
+
+        Ok(())
+    }
+}
+
+/// Reason for dropping a certificate offered by an upstream agent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rejection {
+    NotYetValid,
+    Expired,
+    Principals,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::NotYetValid => "not yet valid",
+            Rejection::Expired => "expired",
+            Rejection::Principals => "no matching principals",
+        })
+    }
+}
+
+/// Certificates loaded from [`CertificateConfig::paths`], re-read whenever the set of files or
+/// any of their modification times change
+#[derive(Debug, Default)]
+pub(crate) struct AttachedCertificates {
+    paths: Vec<PathBuf>,
+    mtimes: BTreeMap<PathBuf, SystemTime>,
+    certs: Vec<Certificate>,
+    // Encoded certificates, which unlike `Certificate` itself compare without the comment
+    encoded: HashSet<Vec<u8>>,
+}
+
+impl AttachedCertificates {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        Self {
+            paths,
+            ..Default::default()
+        }
+    }
+
+    /// Re-read certificate files if any have been added, removed, or modified since last loaded
+    pub fn reload_if_changed(&mut self) {
+        if self.paths.is_empty() {
+            return;
+        }
+
+        let mtimes: BTreeMap<_, _> = self
+            .paths
+            .iter()
+            .flat_map(|p| match cert_files(p) {
+                Ok(files) => files,
+                Err(e) => {
+                    log::warn!("Couldn't read certificates from {}: {}", p.display(), e);
+                    vec![]
+                }
+            })
+            .filter_map(|p| {
+                let mtime = p.metadata().and_then(|m| m.modified()).ok()?;
+                Some((p, mtime))
+            })
+            .collect();
+        if mtimes == self.mtimes {
+            return;
+        }
+
+        log::debug!("Loading {} attached certificate files", mtimes.len());
+        self.certs.clear();
+        self.encoded.clear();
+        for path in mtimes.keys() {
+            match Certificate::read_file(path) {
+                Ok(cert) => {
+                    log::trace!(
+                        "Loaded certificate {:?} from {}",
+                        cert.key_id(),
+                        path.display()
+                    );
+                    if let Ok(bytes) = cert.to_bytes() {
+                        self.encoded.insert(bytes);
+                    }
+                    self.certs.push(cert);
+                }
+                Err(e) => log::warn!("Ignoring invalid certificate {}: {}", path.display(), e),
+            }
+        }
+        self.mtimes = mtimes;
+    }
+
+    /// Certificates issued for `pubkey`
+    pub fn for_key<'a>(&'a self, pubkey: &'a PubKeyData) -> impl Iterator<Item = &'a Certificate> {
+        self.certs.iter().filter(move |c| c.public_key() == pubkey)
+    }
+
+    /// Whether `cert` was loaded from disk, rather than offered by an upstream agent
+    pub fn contains(&self, cert: &Certificate) -> bool {
+        cert.to_bytes()
+            .is_ok_and(|bytes| self.encoded.contains(&bytes))
+    }
+}
+
+/// Expand `path` to a list of certificate files: the path itself, if it's a file, or all
+/// `*-cert.pub` files within, if it's a directory
+fn cert_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = vec![];
+    for entry in fs::read_dir(path)? {
+        let entry_path = entry?.path();
+        let is_cert = entry_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with(CERT_FILE_SUFFIX));
+        if is_cert && entry_path.is_file() {
+            files.push(entry_path);
+        }
+    }
+    Ok(files)
+}
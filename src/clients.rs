@@ -0,0 +1,310 @@
+//! Restricting which local users can use the mux, by the credentials of each client connection,
+//! and which keys each client program can use
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::ssh_key::{public::KeyData as PubKeyData, PublicKey};
+
+use crate::verify::{self, PeerCredentials};
+
+/// Limit on how many ancestors of a client process are looked at
+const MAX_ANCESTORS: usize = 32;
+
+/// Users and groups allowed to connect to the mux, besides the user running it
+///
+/// Without any, only processes running as the same user as the mux are accepted, even if the
+/// socket's permissions let others connect.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AllowedClients {
+    /// User IDs allowed to connect
+    pub uids: Vec<u32>,
+
+    /// Group IDs allowed to connect, matched against each client's effective group only
+    pub gids: Vec<u32>,
+}
+
+impl AllowedClients {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub(crate) fn allows(&self, peer: &PeerCredentials) -> bool {
+        peer.uid == verify::current_uid()
+            || self.uids.contains(&peer.uid)
+            || self.gids.contains(&peer.gid)
+    }
+}
+
+/// Keys that client programs may use, chosen by the program's executable
+///
+/// The client of the mux is usually `ssh` or `ssh-keygen`, started by the program that needs a
+/// key, such as `git`, so a policy applies when its programs match either the client process or
+/// any process that started it.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientPolicy {
+    /// Executable paths or glob patterns, or program names, that this policy applies to
+    ///
+    /// A policy without any applies to every client that no other policy applies to.
+    pub programs: Vec<String>,
+
+    /// Fingerprints (`SHA256:...`) or public keys of the only keys that clients may use
+    ///
+    /// If empty, clients may use any key.
+    pub keys: Vec<String>,
+
+    /// Socket paths or glob patterns of the only upstream agents whose keys clients may use
+    ///
+    /// If empty, clients may use keys from any upstream.
+    pub upstreams: Vec<PathBuf>,
+
+    /// Socket paths or glob patterns of upstream agents whose keys clients may never use
+    pub deny_upstreams: Vec<PathBuf>,
+}
+
+impl ClientPolicy {
+    /// Choose the policy for `client`: the first whose programs match the client process, or
+    /// else the nearest process that started it, or else the first without any programs
+    pub(crate) fn select<'a>(policies: &'a [Self], client: &ClientProcess) -> Option<&'a Self> {
+        client
+            .chain
+            .iter()
+            .find_map(|process| {
+                policies
+                    .iter()
+                    .find(|p| p.programs.iter().any(|program| process.is(program)))
+            })
+            .or_else(|| policies.iter().find(|p| p.programs.is_empty()))
+    }
+
+    /// Whether clients may use `key`, held by the upstream agent named `upstream`
+    pub(crate) fn allows(&self, key: &PubKeyData, upstream: &str) -> bool {
+        let key_allowed = self.keys.is_empty() || key_listed(&self.keys, key);
+        let matches_upstream = |pattern: &PathBuf| {
+            glob::Pattern::new(&pattern.to_string_lossy()).is_ok_and(|p| p.matches(upstream))
+        };
+        key_allowed
+            && (self.upstreams.is_empty() || self.upstreams.iter().any(matches_upstream))
+            && !self.deny_upstreams.iter().any(matches_upstream)
+    }
+}
+
+/// Whether `key` is one of `keys`, given as fingerprints (`SHA256:...`) or public keys
+pub(crate) fn key_listed(keys: &[String], key: &PubKeyData) -> bool {
+    fingerprint_listed(keys, &key.fingerprint(Default::default()).to_string())
+}
+
+/// Whether the key with `fingerprint` is one of `keys`, given as fingerprints or public keys
+pub(crate) fn fingerprint_listed(keys: &[String], fingerprint: &str) -> bool {
+    keys.iter().any(|k| {
+        k == fingerprint
+            || PublicKey::from_openssh(k)
+                .is_ok_and(|k| k.fingerprint(Default::default()).to_string() == fingerprint)
+    })
+}
+
+/// Whether `exe` is `program`, an executable path or glob pattern (anything containing `/`), or
+/// the file name of an executable
+pub(crate) fn program_matches(exe: &Path, program: &str) -> bool {
+    if program.contains('/') {
+        return glob::Pattern::new(program).is_ok_and(|p| p.matches_path(exe));
+    }
+    exe.file_name().and_then(|n| n.to_str()) == Some(program)
+}
+
+/// A process that is, or started, a client of the mux
+#[derive(Clone, Debug)]
+struct ProcessInfo {
+    pid: i32,
+    exe: Option<PathBuf>,
+    /// Name of the program, which for scripts is the script's name rather than the interpreter's
+    ///
+    /// Only for logging: any process can change its own name, so it mustn't choose a policy.
+    comm: Option<String>,
+}
+
+impl ProcessInfo {
+    fn read(pid: i32) -> Self {
+        let proc_dir = PathBuf::from(format!("/proc/{pid}"));
+        Self {
+            pid,
+            exe: fs::read_link(proc_dir.join("exe")).ok(),
+            comm: fs::read_to_string(proc_dir.join("comm"))
+                .ok()
+                .map(|c| c.trim_end_matches('\n').to_string()),
+        }
+    }
+
+    /// Whether this process runs `program`, as [`program_matches`] matches it
+    fn is(&self, program: &str) -> bool {
+        self.exe
+            .as_deref()
+            .is_some_and(|exe| program_matches(exe, program))
+    }
+
+    fn parent_pid(&self) -> Option<i32> {
+        // The process name in parentheses may contain spaces, so skip past it
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.pid)).ok()?;
+        let (_, rest) = stat.rsplit_once(')')?;
+        rest.split_whitespace().nth(1)?.parse().ok()
+    }
+}
+
+/// The client process at the other end of a connection to the mux, and the processes that
+/// started it, as far as they can be found
+///
+/// Processes are found through `/proc`, so this is empty on other platforms than Linux.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ClientProcess {
+    credentials: Option<PeerCredentials>,
+    /// Nearest first
+    chain: Vec<ProcessInfo>,
+}
+
+impl ClientProcess {
+    pub fn credentials(&self) -> Option<&PeerCredentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn pid(&self) -> Option<i32> {
+        self.credentials.and_then(|c| c.pid)
+    }
+
+    pub fn exe(&self) -> Option<&Path> {
+        self.chain.first()?.exe.as_deref()
+    }
+
+    /// Executables of the client and the processes that started it, nearest first
+    pub fn exes(&self) -> Vec<PathBuf> {
+        self.chain.iter().filter_map(|p| p.exe.clone()).collect()
+    }
+
+    pub fn of(peer: &PeerCredentials) -> Self {
+        let mut chain = vec![];
+        let mut next = peer.pid;
+        while let Some(pid) = next.filter(|&pid| pid > 1 && chain.len() < MAX_ANCESTORS) {
+            let process = ProcessInfo::read(pid);
+            next = process.parent_pid();
+            chain.push(process);
+        }
+        Self {
+            credentials: Some(*peer),
+            chain,
+        }
+    }
+}
+
+impl fmt::Display for ClientProcess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.chain.first() {
+            Some(process) => {
+                let name = process
+                    .exe
+                    .as_deref()
+                    .map(|exe| exe.display().to_string())
+                    .or_else(|| process.comm.clone())
+                    .unwrap_or_else(|| "unknown program".into());
+                write!(f, "{} (pid {})", name, process.pid)
+            }
+            None => f.write_str("unknown client"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(uid: u32, gid: u32) -> PeerCredentials {
+        PeerCredentials {
+            uid,
+            gid,
+            pid: None,
+        }
+    }
+
+    #[test]
+    fn own_uid_always_allowed() {
+        let own = verify::current_uid();
+        let other = own.wrapping_add(1);
+        assert!(AllowedClients::default().allows(&peer(own, other)));
+        assert!(!AllowedClients::default().allows(&peer(other, other)));
+    }
+
+    #[test]
+    fn allowlisted_uids_and_gids() {
+        let other = verify::current_uid().wrapping_add(1);
+        let allowed = AllowedClients {
+            uids: vec![other],
+            gids: vec![4242],
+        };
+        assert!(allowed.allows(&peer(other, 0)));
+        assert!(allowed.allows(&peer(other.wrapping_add(1), 4242)));
+        assert!(!allowed.allows(&peer(other.wrapping_add(1), 4243)));
+    }
+
+    fn process(exe: &str, comm: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid: 2,
+            exe: Some(exe.into()),
+            comm: Some(comm.into()),
+        }
+    }
+
+    fn policy(programs: &[&str], keys: &[&str]) -> ClientPolicy {
+        ClientPolicy {
+            programs: programs.iter().map(|p| p.to_string()).collect(),
+            keys: keys.iter().map(|k| k.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn programs_matched_by_path_or_name() {
+        let git = process("/usr/bin/git", "git");
+        assert!(git.is("git"));
+        assert!(git.is("/usr/bin/git"));
+        assert!(git.is("/usr/*/git"));
+        assert!(!git.is("/usr/local/bin/git"));
+        assert!(!git.is("gi"));
+
+        // Processes can name themselves anything, so only their executable counts
+        let renamed = process("/usr/bin/python3.12", "git");
+        assert!(!renamed.is("git"));
+        assert!(renamed.is("python3.12"));
+    }
+
+    #[test]
+    fn nearest_matching_process_chooses_policy() {
+        let policies = [
+            policy(&["bash"], &["bash"]),
+            policy(&["git"], &["git"]),
+            policy(&[], &["default"]),
+        ];
+        let client = |chain: Vec<ProcessInfo>| ClientProcess {
+            credentials: None,
+            chain,
+        };
+
+        let from_git = client(vec![
+            process("/usr/bin/ssh", "ssh"),
+            process("/usr/bin/git", "git"),
+            process("/bin/bash", "bash"),
+        ]);
+        assert_eq!(
+            ClientPolicy::select(&policies, &from_git).unwrap().keys,
+            ["git"]
+        );
+        let from_ssh = client(vec![process("/usr/bin/ssh", "ssh")]);
+        assert_eq!(
+            ClientPolicy::select(&policies, &from_ssh).unwrap().keys,
+            ["default"]
+        );
+        assert_eq!(ClientPolicy::select(&policies[..2], &from_ssh), None);
+    }
+}
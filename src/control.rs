@@ -0,0 +1,382 @@
+//! A second socket, next to the listen socket, for administering a running mux with JSON requests
+//! and responses, one per line
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream as StdUnixStream,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::{Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::{agent::ListeningSocket, ssh_key::public::KeyData as PubKeyData};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
+    net::UnixStream,
+    sync::mpsc,
+};
+
+use crate::{MuxAgent, SelfDeletingUnixListener};
+
+/// Path of the control socket of a mux listening on `listen_path`
+pub fn control_path(listen_path: &Path) -> PathBuf {
+    listen_path.with_extension("ctl")
+}
+
+/// A request to the control socket
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Summary of the mux's state
+    Status,
+    /// Upstream agents, and their health
+    Upstreams,
+    /// Keys offered by upstream agents when they were last listed, listing them first if they
+    /// never were, or the upstream sockets changed since
+    Keys,
+    /// List keys from upstream agents again, retrying failing ones immediately
+    Refresh,
+    /// Use an upstream agent again, after disabling it
+    Enable { upstream: String },
+    /// Stop using an upstream agent, until it's enabled again or the mux restarts
+    Disable { upstream: String },
+    /// Change the log level, to `error`, `warn`, `info`, `debug`, or `trace`
+    LogLevel { level: String },
+    /// Lock the mux, as `ssh-add -x` does
+    Lock { passphrase: String },
+    /// Unlock the mux, as `ssh-add -X` does
+    Unlock { passphrase: String },
+    /// Read the configuration file again
+    Reload,
+}
+
+/// A response from the control socket
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstreams: Option<Vec<UpstreamStatus>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<KeyStatus>>,
+}
+
+impl ControlResponse {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Default::default()
+        }
+    }
+
+    fn error(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(error.into()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Status {
+    pub version: String,
+    pub listen_path: PathBuf,
+    pub locked: bool,
+    /// Enabled upstream agents
+    pub upstreams: usize,
+    /// Enabled upstream agents that aren't failing
+    pub healthy_upstreams: usize,
+    pub keys: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UpstreamStatus {
+    pub name: String,
+    pub enabled: bool,
+    pub healthy: bool,
+    pub consecutive_failures: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// RFC 3339, in UTC
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_success: Option<String>,
+    /// Time taken to list keys, at the last success
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<f64>,
+    /// While failing, how long until the upstream is tried again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_in_ms: Option<u64>,
+    pub keys: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct KeyStatus {
+    pub fingerprint: String,
+    pub algorithm: String,
+    pub comment: String,
+    pub upstream: String,
+    /// In OpenSSH format, without a comment
+    pub public_key: String,
+}
+
+/// Requests that the program running the mux has to carry out itself
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlEvent {
+    Reload,
+    LogLevel(log::LevelFilter),
+}
+
+/// The channel for events the program running the mux must handle
+pub type ControlEvents = mpsc::UnboundedSender<ControlEvent>;
+
+/// Send `request` to the control socket at `path`, and wait for the response
+pub fn control_request(path: &Path, request: &ControlRequest) -> io::Result<ControlResponse> {
+    let mut stream = StdUnixStream::connect(path)?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+impl MuxAgent {
+    /// Accept connections to the control socket, handling each in the background
+    pub(crate) async fn serve_control(
+        &self,
+        mut listener: SelfDeletingUnixListener,
+    ) -> Result<(), ssh_agent_lib::error::AgentError> {
+        loop {
+            let stream = listener.accept().await?;
+            let mut this = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = this.handle_control_connection(stream).await {
+                    log::debug!("Control connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_control_connection(&mut self, stream: UnixStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = AsyncBufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let (response, event) = match serde_json::from_str(&line) {
+                Ok(request) => {
+                    log::debug!("Control request: {:?}", request);
+                    self.handle_control(request).await
+                }
+                Err(e) => (
+                    ControlResponse::error(format!("invalid request: {e}")),
+                    None,
+                ),
+            };
+            let mut line = serde_json::to_vec(&response)?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+            writer.flush().await?;
+            // Only after responding, because reloading stops this mux
+            if let Some(event) = event {
+                self.send_control_event(event);
+            }
+        }
+        Ok(())
+    }
+
+    fn send_control_event(&self, event: ControlEvent) {
+        if let Some(events) = &self.options.control_events {
+            let _ = events.send(event);
+        }
+    }
+
+    async fn handle_control(
+        &mut self,
+        request: ControlRequest,
+    ) -> (ControlResponse, Option<ControlEvent>) {
+        let response = match request {
+            ControlRequest::Status => ControlResponse {
+                status: Some(self.status().await),
+                ..ControlResponse::ok()
+            },
+            ControlRequest::Upstreams => ControlResponse {
+                upstreams: Some(self.upstream_statuses().await),
+                ..ControlResponse::ok()
+            },
+            ControlRequest::Keys => {
+                // Keys are only listed when clients ask for them, so none may be known yet
+                if !self.listed_once.load(Ordering::Relaxed) || self.stale.load(Ordering::Relaxed) {
+                    let mut known_keys = self.known_keys.clone().lock_owned().await;
+                    if let Err(e) = self.refresh_identities(&mut known_keys).await {
+                        return (ControlResponse::error(e.to_string()), None);
+                    }
+                }
+                ControlResponse {
+                    keys: Some(self.key_statuses().await),
+                    ..ControlResponse::ok()
+                }
+            }
+            ControlRequest::Refresh => {
+                self.health.lock().await.reset_backoff();
+                let mut known_keys = self.known_keys.clone().lock_owned().await;
+                match self.refresh_identities(&mut known_keys).await {
+                    Ok(_) => {
+                        drop(known_keys);
+                        ControlResponse {
+                            keys: Some(self.key_statuses().await),
+                            ..ControlResponse::ok()
+                        }
+                    }
+                    Err(e) => ControlResponse::error(e.to_string()),
+                }
+            }
+            ControlRequest::Enable { upstream } => self.set_enabled(&upstream, true),
+            ControlRequest::Disable { upstream } => self.set_enabled(&upstream, false),
+            ControlRequest::LogLevel { level } => {
+                if self.options.control_events.is_none() {
+                    return (ControlResponse::error("not supported"), None);
+                }
+                return match level.parse() {
+                    Ok(level) => {
+                        log::info!("Changing log level to {}", level);
+                        (ControlResponse::ok(), Some(ControlEvent::LogLevel(level)))
+                    }
+                    Err(_) => (
+                        ControlResponse::error(format!("unknown log level {level:?}")),
+                        None,
+                    ),
+                };
+            }
+            ControlRequest::Lock { passphrase } => {
+                if self.lock_state.lock().await.lock(passphrase) {
+                    log::info!("Locked through the control socket");
+                    ControlResponse::ok()
+                } else {
+                    ControlResponse::error("already locked")
+                }
+            }
+            ControlRequest::Unlock { passphrase } => {
+                if self.unlock_with(&passphrase, None).await {
+                    log::info!("Unlocked through the control socket");
+                    ControlResponse::ok()
+                } else {
+                    ControlResponse::error("not locked, or wrong passphrase")
+                }
+            }
+            ControlRequest::Reload => {
+                if self.options.control_events.is_none() {
+                    return (ControlResponse::error("not supported"), None);
+                }
+                return (ControlResponse::ok(), Some(ControlEvent::Reload));
+            }
+        };
+        (response, None)
+    }
+
+    async fn status(&self) -> Status {
+        let upstreams = self.upstreams();
+        let health = self.health.lock().await;
+        let healthy_upstreams = upstreams
+            .iter()
+            .filter(|u| health.get(&u.to_string()).is_none_or(|h| h.is_healthy()))
+            .count();
+        drop(health);
+        Status {
+            version: env!("CARGO_PKG_VERSION").into(),
+            listen_path: self.listen_path.clone(),
+            locked: self.is_locked().await,
+            upstreams: upstreams.len(),
+            healthy_upstreams,
+            keys: self.known_keys.lock().await.len(),
+        }
+    }
+
+    async fn upstream_statuses(&self) -> Vec<UpstreamStatus> {
+        let known_keys = self.known_keys.lock().await;
+        let health = self.health.lock().await;
+        let now = Instant::now();
+        self.all_upstreams()
+            .into_iter()
+            .map(|(upstream, enabled)| {
+                let name = upstream.to_string();
+                let h = health.get(&name).cloned().unwrap_or_default();
+                UpstreamStatus {
+                    enabled,
+                    healthy: h.is_healthy(),
+                    consecutive_failures: h.consecutive_failures,
+                    last_error: h.last_error,
+                    last_success: h.last_success.map(rfc3339),
+                    latency_ms: h.latency.map(|l| l.as_secs_f64() * 1000.0),
+                    retry_in_ms: h
+                        .retry_at
+                        .filter(|&retry_at| retry_at > now)
+                        .map(|retry_at| (retry_at - now).as_millis() as u64),
+                    keys: known_keys
+                        .values()
+                        .filter(|k| k.upstream.to_string() == name)
+                        .count(),
+                    name,
+                }
+            })
+            .collect()
+    }
+
+    async fn key_statuses(&self) -> Vec<KeyStatus> {
+        let known_keys = self.known_keys.lock().await;
+        let mut keys: Vec<_> = known_keys
+            .iter()
+            .map(|(key, known)| KeyStatus {
+                fingerprint: key.fingerprint(Default::default()).to_string(),
+                algorithm: key.algorithm().to_string(),
+                comment: known.comment.clone(),
+                upstream: known.upstream.to_string(),
+                public_key: public_key(key),
+            })
+            .collect();
+        keys.sort_by(|a, b| (&a.upstream, &a.fingerprint).cmp(&(&b.upstream, &b.fingerprint)));
+        keys
+    }
+
+    fn set_enabled(&self, name: &str, enabled: bool) -> ControlResponse {
+        let known = self
+            .all_upstreams()
+            .iter()
+            .any(|(u, _)| u.to_string() == name)
+            || self.upstreams.iter().any(|u| u.to_string() == name);
+        if !known {
+            return ControlResponse::error(format!("no upstream agent {name:?}"));
+        }
+        let mut disabled = self
+            .disabled
+            .lock()
+            .expect("disabled upstreams lock poisoned");
+        let changed = if enabled {
+            disabled.remove(name)
+        } else {
+            disabled.insert(name.into())
+        };
+        if changed {
+            log::info!(
+                "{} upstream agent <{}>",
+                if enabled { "Enabled" } else { "Disabled" },
+                name
+            );
+            self.stale.store(true, Ordering::Relaxed);
+        }
+        ControlResponse::ok()
+    }
+}
+
+fn public_key(key: &PubKeyData) -> String {
+    ssh_agent_lib::ssh_key::PublicKey::from(key.clone())
+        .to_openssh()
+        .unwrap_or_default()
+}
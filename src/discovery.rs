@@ -0,0 +1,131 @@
+//! Discovery of upstream agent sockets matching glob patterns, and selection among them
+
+use std::{
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::verify;
+
+/// Settings for a group of candidate sockets for what is effectively one upstream agent, such as
+/// an agent forwarded by SSH whose socket path changes with each connection
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    /// Candidate socket paths, each of which may be a glob pattern
+    pub sockets: Vec<PathBuf>,
+
+    /// How to choose among the candidates that exist
+    pub strategy: GroupStrategy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupStrategy {
+    /// The first candidate, in configured and then alphabetical order, that accepts a connection
+    #[default]
+    FirstAlive,
+    /// The most recently modified candidate that accepts a connection
+    Newest,
+    /// Every candidate
+    All,
+}
+
+impl GroupConfig {
+    /// Choose sockets from the candidates that currently exist; `exclude` is the mux's own
+    /// sockets
+    pub(crate) fn select(&self, exclude: &[PathBuf]) -> Vec<PathBuf> {
+        let mut candidates: Vec<PathBuf> = vec![];
+        for pattern in &self.sockets {
+            for path in glob_sockets(pattern, exclude) {
+                if !candidates.contains(&path) {
+                    candidates.push(path);
+                }
+            }
+        }
+
+        let selected = match self.strategy {
+            GroupStrategy::All => return candidates,
+            GroupStrategy::FirstAlive => candidates.into_iter().find(|p| is_alive(p)),
+            GroupStrategy::Newest => {
+                let mut by_mtime: Vec<(SystemTime, PathBuf)> = candidates
+                    .into_iter()
+                    .filter_map(|p| Some((fs::metadata(&p).ok()?.modified().ok()?, p)))
+                    .collect();
+                // Stable sort, so equally new sockets keep their configured order
+                by_mtime.sort_by(|(a, _), (b, _)| b.cmp(a));
+                by_mtime.into_iter().map(|(_, p)| p).find(|p| is_alive(p))
+            }
+        };
+        log::trace!(
+            "Selected {} from group {:?}",
+            selected
+                .as_deref()
+                .map_or_else(|| "no socket".into(), |p| p.display().to_string()),
+            self.sockets
+        );
+        selected.into_iter().collect()
+    }
+}
+
+/// Whether an agent is listening on the socket at `path`
+fn is_alive(path: &Path) -> bool {
+    UnixStream::connect(path).is_ok()
+}
+
+/// Find the agent sockets matching `pattern` that are owned by the current user, in sorted order
+///
+/// `exclude` is the mux's own listening and control sockets, which must never be treated as
+/// upstreams.
+pub(crate) fn glob_sockets(pattern: &Path, exclude: &[PathBuf]) -> Vec<PathBuf> {
+    let pattern_str = pattern.to_string_lossy();
+    let matches = match glob::glob(&pattern_str) {
+        Ok(matches) => matches,
+        Err(e) => {
+            log::warn!("Invalid upstream glob {}: {}", pattern_str, e);
+            return vec![];
+        }
+    };
+
+    let uid = verify::current_uid();
+    let excluded: Vec<_> = exclude
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|m| (m.dev(), m.ino()))
+        .collect();
+    let mut sockets = vec![];
+    for path in matches.filter_map(Result::ok) {
+        let Ok(metadata) = fs::metadata(&path) else {
+            continue;
+        };
+        if !metadata.file_type().is_socket() {
+            continue;
+        }
+        if metadata.uid() != uid {
+            log::debug!(
+                "Skipping socket {} owned by uid {}",
+                path.display(),
+                metadata.uid()
+            );
+            continue;
+        }
+        if excluded.contains(&(metadata.dev(), metadata.ino())) {
+            continue;
+        }
+        sockets.push(path);
+    }
+    // glob already yields paths in alphabetical order
+    log::trace!(
+        "Upstream glob {} matched {} sockets",
+        pattern_str,
+        sockets.len()
+    );
+    sockets
+}
@@ -0,0 +1,290 @@
+//! Upstream agents reached through the standard input and output of a child process
+
+use std::{
+    fmt,
+    future::Future,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ssh_agent_lib::{
+    agent::Session,
+    client::Client,
+    error::AgentError,
+    proto::{Extension, Identity, SignRequest},
+    ssh_key::Signature,
+};
+use tokio::process::{Child, Command};
+
+/// Delay before restarting a command that exited or failed, doubled after each further failure
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// How long a command may take to answer a request, before it's killed and restarted
+const LIST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longer, because signing may wait for the user to touch a security key or enter a PIN
+const SIGN_TIMEOUT: Duration = Duration::from_secs(60);
+
+type SharedClient = Arc<tokio::sync::Mutex<Box<dyn Session>>>;
+
+struct Running {
+    child: Child,
+    client: SharedClient,
+    generation: u64,
+}
+
+#[derive(Default)]
+struct ExecState {
+    running: Option<Running>,
+    // Incremented for each child, so errors from an old child can't stop its replacement
+    generation: u64,
+    failures: u32,
+    restart_at: Option<Instant>,
+}
+
+/// A command speaking the SSH agent protocol on its standard input and output, such as
+/// `socat - UNIX-CONNECT:/path/to/agent.sock`
+///
+/// The command is started on first use and kept running. If it exits, stops answering, or the
+/// connection to it fails, it is restarted on next use, after a delay that grows with each
+/// consecutive failure.
+#[derive(Clone)]
+pub(crate) struct ExecUpstream {
+    command: Vec<String>,
+    state: Arc<Mutex<ExecState>>,
+    list_timeout: Duration,
+    sign_timeout: Duration,
+}
+
+impl ExecUpstream {
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            state: Default::default(),
+            list_timeout: LIST_TIMEOUT,
+            sign_timeout: SIGN_TIMEOUT,
+        }
+    }
+
+    pub fn connect(&self) -> Result<Box<dyn Session>, AgentError> {
+        let mut state = self.state();
+        if let Some(running) = &mut state.running {
+            match running.child.try_wait() {
+                Ok(None) => {
+                    return Ok(Box::new(ExecSession {
+                        upstream: self.clone(),
+                        client: running.client.clone(),
+                        generation: running.generation,
+                    }))
+                }
+                Ok(Some(status)) => log::warn!("Upstream command <{}> exited: {}", self, status),
+                Err(e) => log::warn!("Upstream command <{}> failed: {}", self, e),
+            }
+            state.running = None;
+            self.schedule_restart(&mut state);
+        }
+
+        if let Some(restart_at) = state.restart_at {
+            let now = Instant::now();
+            if now < restart_at {
+                return Err(AgentError::Other(
+                    format!(
+                        "Upstream command <{}> is restarting in {} seconds",
+                        self,
+                        (restart_at - now).as_secs_f32().ceil()
+                    )
+                    .into(),
+                ));
+            }
+        }
+
+        let mut child = match self.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                self.schedule_restart(&mut state);
+                return Err(AgentError::Other(
+                    format!("Failed to start upstream command <{}>: {}", self, e).into(),
+                ));
+            }
+        };
+        log::info!(
+            "Started upstream command <{}> with pid {}",
+            self,
+            child.id().unwrap_or_default()
+        );
+        let stdio = tokio::io::join(
+            child.stdout.take().expect("stdout is piped"),
+            child.stdin.take().expect("stdin is piped"),
+        );
+        let client: SharedClient = Arc::new(tokio::sync::Mutex::new(Box::new(Client::new(stdio))));
+        state.generation += 1;
+        state.running = Some(Running {
+            child,
+            client: client.clone(),
+            generation: state.generation,
+        });
+        Ok(Box::new(ExecSession {
+            upstream: self.clone(),
+            client,
+            generation: state.generation,
+        }))
+    }
+
+    fn spawn(&self) -> std::io::Result<Child> {
+        let (program, args) = self.command.split_first().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "empty command")
+        })?;
+        Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, ExecState> {
+        self.state.lock().expect("exec upstream lock poisoned")
+    }
+
+    fn schedule_restart(&self, state: &mut ExecState) {
+        state.failures = state.failures.saturating_add(1);
+        let backoff = RESTART_BACKOFF_MIN
+            .saturating_mul(1 << state.failures.saturating_sub(1).min(16))
+            .min(RESTART_BACKOFF_MAX);
+        log::debug!(
+            "Restarting upstream command <{}> in {} seconds",
+            self,
+            backoff.as_secs()
+        );
+        state.restart_at = Some(Instant::now() + backoff);
+    }
+
+    /// Stop the child for `generation` after the connection to it failed, so it is restarted
+    fn stop(&self, generation: u64, error: &AgentError) {
+        let mut state = self.state();
+        if state
+            .running
+            .as_ref()
+            .is_some_and(|r| r.generation == generation)
+        {
+            log::warn!(
+                "Stopping upstream command <{}> after connection failure: {}",
+                self,
+                error
+            );
+            // Dropping the child kills it
+            state.running = None;
+            self.schedule_restart(&mut state);
+        }
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.state();
+        state.failures = 0;
+        state.restart_at = None;
+    }
+}
+
+impl fmt::Debug for ExecUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExecUpstream")
+            .field("command", &self.command)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for ExecUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.command.join(" "))
+    }
+}
+
+/// Session with a running command, shared by locking the connection for each request
+///
+/// Only the requests that [`crate::MuxAgent`] forwards to upstream agents are supported.
+struct ExecSession {
+    upstream: ExecUpstream,
+    client: SharedClient,
+    generation: u64,
+}
+
+impl ExecSession {
+    /// Wait for `request` to the command for up to `timeout`, treating a timeout as a broken
+    /// connection, so that the command is killed and restarted
+    async fn round_trip<T>(
+        &self,
+        timeout: Duration,
+        request: impl Future<Output = Result<T, AgentError>>,
+    ) -> Result<T, AgentError> {
+        let result = tokio::time::timeout(timeout, request)
+            .await
+            .unwrap_or_else(|_| {
+                Err(AgentError::IO(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no response within {} seconds", timeout.as_secs_f32()),
+                )))
+            });
+        self.check(result)
+    }
+
+    fn check<T>(&self, result: Result<T, AgentError>) -> Result<T, AgentError> {
+        match &result {
+            // Errors in the connection itself, rather than refusals by the upstream agent
+            Err(e @ (AgentError::IO(_) | AgentError::Proto(_))) => {
+                self.upstream.stop(self.generation, e)
+            }
+            _ => self.upstream.succeeded(),
+        }
+        result
+    }
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for ExecSession {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        let client = self.client.clone();
+        self.round_trip(self.upstream.list_timeout, async move {
+            client.lock().await.request_identities().await
+        })
+        .await
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        let client = self.client.clone();
+        self.round_trip(self.upstream.sign_timeout, async move {
+            client.lock().await.sign(request).await
+        })
+        .await
+    }
+
+    async fn extension(&mut self, extension: Extension) -> Result<Option<Extension>, AgentError> {
+        let client = self.client.clone();
+        self.round_trip(self.upstream.list_timeout, async move {
+            client.lock().await.extension(extension).await
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unresponsive_command_restarted() {
+        // Reads requests, but never answers them
+        let mut upstream =
+            ExecUpstream::new(["sh", "-c", "cat >/dev/null"].map(String::from).to_vec());
+        upstream.list_timeout = Duration::from_millis(200);
+        let mut session = upstream.connect().unwrap();
+
+        let started = Instant::now();
+        assert!(session.request_identities().await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let state = upstream.state();
+        assert!(state.running.is_none());
+        assert!(state.restart_at.is_some());
+    }
+}
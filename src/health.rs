@@ -0,0 +1,198 @@
+//! Tracking the health of upstream agents, so that failing ones are skipped for a while instead of
+//! being retried on every request
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Delay before retrying an upstream after its first failure, doubled after each further one
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Recent history of one upstream agent
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Health {
+    pub consecutive_failures: u32,
+    pub last_success: Option<SystemTime>,
+    pub last_error: Option<String>,
+    /// Time taken to list keys, at the last success
+    pub latency: Option<Duration>,
+    /// While failing, the upstream is skipped until this time
+    pub retry_at: Option<Instant>,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.consecutive_failures == 0
+    }
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_healthy() {
+            f.write_str("healthy")?;
+        } else {
+            write!(f, "{} consecutive failures", self.consecutive_failures)?;
+            if let Some(retry_at) = self.retry_at {
+                let wait = retry_at.saturating_duration_since(Instant::now());
+                write!(f, ", retrying in {:.1}s", wait.as_secs_f32())?;
+            }
+        }
+        if let Some(latency) = self.latency {
+            write!(f, ", last listed keys in {}ms", latency.as_millis())?;
+        }
+        Ok(())
+    }
+}
+
+/// Health of every upstream agent, by name
+#[derive(Debug, Default)]
+pub(crate) struct HealthTracker {
+    upstreams: HashMap<String, Health>,
+}
+
+impl HealthTracker {
+    /// Whether `upstream` is failing, and shouldn't be tried again yet
+    pub fn should_skip(&self, upstream: &str, now: Instant) -> bool {
+        self.upstreams
+            .get(upstream)
+            .and_then(|h| h.retry_at)
+            .is_some_and(|retry_at| now < retry_at)
+    }
+
+    /// Returns whether the upstream was failing until now
+    pub fn record_success(&mut self, upstream: &str, latency: Option<Duration>) -> bool {
+        let health = self.upstreams.entry(upstream.into()).or_default();
+        let recovered = !health.is_healthy();
+        if recovered {
+            log::info!(
+                "Upstream agent <{}> is available again, after {} failures",
+                upstream,
+                health.consecutive_failures
+            );
+        }
+        health.consecutive_failures = 0;
+        health.retry_at = None;
+        health.last_success = Some(SystemTime::now());
+        if latency.is_some() {
+            health.latency = latency;
+        }
+        recovered
+    }
+
+    /// Returns whether this is the first failure since the upstream last succeeded
+    pub fn record_failure(
+        &mut self,
+        upstream: &str,
+        error: &dyn fmt::Display,
+        now: Instant,
+    ) -> bool {
+        let health = self.upstreams.entry(upstream.into()).or_default();
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        health.last_error = Some(error.to_string());
+        let backoff = BACKOFF_MIN
+            .saturating_mul(1 << (health.consecutive_failures - 1).min(16))
+            .min(BACKOFF_MAX);
+        health.retry_at = Some(now + backoff);
+
+        // Only warn once each time an upstream starts failing
+        if health.consecutive_failures == 1 {
+            log::warn!(
+                "Ignoring unavailable upstream agent <{}>: {}",
+                upstream,
+                error
+            );
+        } else {
+            log::debug!(
+                "Ignoring unavailable upstream agent <{}>: {}",
+                upstream,
+                error
+            );
+        }
+        log::debug!(
+            "Skipping upstream agent <{}> for {} seconds, after {} consecutive failures",
+            upstream,
+            backoff.as_secs(),
+            health.consecutive_failures
+        );
+        health.consecutive_failures == 1
+    }
+
+    /// Let failing upstreams be retried immediately, such as when their sockets have changed
+    pub fn reset_backoff(&mut self) {
+        for health in self.upstreams.values_mut() {
+            health.retry_at = None;
+        }
+    }
+
+    pub fn get(&self, upstream: &str) -> Option<&Health> {
+        self.upstreams.get(upstream)
+    }
+
+    /// Forget upstreams other than `upstreams`, such as sockets that have gone away
+    pub fn retain(&mut self, upstreams: &[String]) {
+        self.upstreams.retain(|name, _| upstreams.contains(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSTREAM: &str = "/run/agent.sock";
+
+    #[test]
+    fn backoff_grows_and_resets_on_success() {
+        let mut tracker = HealthTracker::default();
+        let now = Instant::now();
+        assert!(!tracker.should_skip(UPSTREAM, now));
+
+        tracker.record_failure(UPSTREAM, &"refused", now);
+        assert!(tracker.should_skip(UPSTREAM, now));
+        assert!(!tracker.should_skip(UPSTREAM, now + Duration::from_secs(1)));
+
+        tracker.record_failure(UPSTREAM, &"refused", now);
+        tracker.record_failure(UPSTREAM, &"refused", now);
+        assert!(tracker.should_skip(UPSTREAM, now + Duration::from_secs(3)));
+        assert!(!tracker.should_skip(UPSTREAM, now + Duration::from_secs(4)));
+        assert_eq!(tracker.get(UPSTREAM).unwrap().consecutive_failures, 3);
+
+        tracker.record_success(UPSTREAM, Some(Duration::from_millis(5)));
+        assert!(!tracker.should_skip(UPSTREAM, now));
+        assert!(tracker.get(UPSTREAM).unwrap().is_healthy());
+    }
+
+    #[test]
+    fn backoff_capped() {
+        let mut tracker = HealthTracker::default();
+        let now = Instant::now();
+        for _ in 0..100 {
+            tracker.record_failure(UPSTREAM, &"refused", now);
+        }
+        assert!(tracker.should_skip(UPSTREAM, now + BACKOFF_MAX - Duration::from_secs(1)));
+        assert!(!tracker.should_skip(UPSTREAM, now + BACKOFF_MAX));
+    }
+
+    #[test]
+    fn reset_backoff_allows_retry() {
+        let mut tracker = HealthTracker::default();
+        let now = Instant::now();
+        tracker.record_failure(UPSTREAM, &"refused", now);
+        tracker.reset_backoff();
+        assert!(!tracker.should_skip(UPSTREAM, now));
+        assert!(!tracker.get(UPSTREAM).unwrap().is_healthy());
+    }
+
+    #[test]
+    fn removed_upstreams_forgotten() {
+        let mut tracker = HealthTracker::default();
+        let now = Instant::now();
+        tracker.record_failure(UPSTREAM, &"refused", now);
+        tracker.record_success("/run/other.sock", None);
+        tracker.retain(&["/run/other.sock".into()]);
+        assert!(tracker.get(UPSTREAM).is_none());
+        assert!(tracker.get("/run/other.sock").is_some());
+    }
+}
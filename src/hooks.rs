@@ -0,0 +1,322 @@
+//! External commands run when something happens in the mux, such as a key being asked to sign,
+//! for notifications and alerting
+
+use std::{process::Stdio, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, Command},
+};
+
+use crate::audit::SignRecord;
+
+/// Default for how long a hook command may run before it's killed, in seconds
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Prefix of the environment variables that event details are passed to hooks in
+const ENV_PREFIX: &str = "SSH_AGENT_MUX_";
+
+/// Environment variables, as names and values
+type Env = Vec<(String, String)>;
+
+/// Kinds of events that hooks can run on
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    /// A signing request passed the mux's policies, and is being sent to an upstream agent
+    SignRequested,
+    /// An upstream agent made a signature
+    SignCompleted,
+    /// A signing request was refused, or failed
+    SignFailed,
+    /// An upstream agent started failing
+    UpstreamOffline,
+    /// A failing upstream agent is available again
+    UpstreamOnline,
+    /// A key that wasn't listed by any upstream agent before is listed now
+    KeyAdded,
+}
+
+/// A command to run on some events
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    /// Events to run the command on
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<HookEvent>,
+
+    /// Run the command before each signing request is sent to an upstream agent, whatever
+    /// `events` are listed, and refuse the request unless it succeeds
+    #[serde(default)]
+    pub gating: bool,
+
+    /// What to do with a signing request if a gating command times out
+    #[serde(default)]
+    pub on_timeout: OnTimeout,
+
+    /// Command to run, with the event's details as JSON on its standard input, and in
+    /// `SSH_AGENT_MUX_*` environment variables
+    pub command: Vec<String>,
+
+    /// How long the command may run before it's killed, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnTimeout {
+    #[default]
+    Deny,
+    Allow,
+}
+
+/// Something that happened, with its details
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum Event {
+    SignRequested(SignRecord),
+    SignCompleted(SignRecord),
+    SignFailed(SignRecord),
+    UpstreamOffline {
+        upstream: String,
+        error: String,
+    },
+    UpstreamOnline {
+        upstream: String,
+    },
+    KeyAdded {
+        fingerprint: String,
+        comment: String,
+        upstream: String,
+    },
+}
+
+impl Event {
+    pub fn kind(&self) -> HookEvent {
+        match self {
+            Self::SignRequested(_) => HookEvent::SignRequested,
+            Self::SignCompleted(_) => HookEvent::SignCompleted,
+            Self::SignFailed(_) => HookEvent::SignFailed,
+            Self::UpstreamOffline { .. } => HookEvent::UpstreamOffline,
+            Self::UpstreamOnline { .. } => HookEvent::UpstreamOnline,
+            Self::KeyAdded { .. } => HookEvent::KeyAdded,
+        }
+    }
+}
+
+impl Hook {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+    }
+}
+
+/// Run the notification hooks for `event` in the background, without waiting for them
+pub(crate) fn notify(hooks: &[Hook], event: &Event) {
+    let kind = event.kind();
+    let mut matching = hooks
+        .iter()
+        .filter(|h| !h.gating && h.events.contains(&kind))
+        .peekable();
+    if matching.peek().is_none() {
+        return;
+    }
+    let Some((env, input)) = details(event) else {
+        return;
+    };
+    for hook in matching {
+        spawn(&hook.command, &env, Some(input.clone()), hook.timeout());
+    }
+}
+
+/// Run the gating hooks for a signing request, one at a time, and return whether they all allow
+/// it
+pub(crate) async fn authorize(hooks: &[Hook], event: &Event) -> bool {
+    let gating: Vec<_> = hooks.iter().filter(|h| h.gating).collect();
+    if gating.is_empty() {
+        return true;
+    }
+    let Some((env, input)) = details(event) else {
+        return false;
+    };
+    for hook in gating {
+        let Some(child) = start(&hook.command, &env, true) else {
+            return false;
+        };
+        // Refusals are logged by the caller, so failing isn't worth a warning of its own
+        let allowed = wait(
+            &hook.command[0],
+            child,
+            Some(input.clone()),
+            hook.timeout(),
+            log::Level::Debug,
+        );
+        match allowed.await {
+            Some(true) => (),
+            Some(false) => return false,
+            None if hook.on_timeout == OnTimeout::Allow => (),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// The details of `event`, as environment variables and a line of JSON
+fn details(event: &Event) -> Option<(Env, Vec<u8>)> {
+    let details = match serde_json::to_value(event) {
+        Ok(details) => details,
+        Err(e) => {
+            log::error!("Failed to serialize hook event: {}", e);
+            return None;
+        }
+    };
+    let mut env = vec![];
+    env_vars(ENV_PREFIX.into(), &details, &mut env);
+    let mut input = details.to_string().into_bytes();
+    input.push(b'\n');
+    Some((env, input))
+}
+
+/// Start `command` with `env` and `input` on its standard input, and log how it ends in the
+/// background, killing it if it takes longer than `timeout`
+pub(crate) fn spawn(
+    command: &[String],
+    env: &[(String, String)],
+    input: Option<Vec<u8>>,
+    timeout: Duration,
+) {
+    let Some(child) = start(command, env, input.is_some()) else {
+        return;
+    };
+    let program = command[0].clone();
+    tokio::spawn(async move { wait(&program, child, input, timeout, log::Level::Warn).await });
+}
+
+fn start(command: &[String], env: &[(String, String)], piped: bool) -> Option<Child> {
+    let (program, args) = command.split_first()?;
+    let child = Command::new(program)
+        .args(args)
+        .envs(env.iter().cloned())
+        .stdin(if piped { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn();
+    match child {
+        Ok(child) => Some(child),
+        Err(e) => {
+            log::warn!("Failed to run hook command {}: {}", program, e);
+            None
+        }
+    }
+}
+
+/// Write `input` to `child` and wait for it to exit, returning whether it succeeded, or `None`
+/// if it was killed after `timeout`
+async fn wait(
+    program: &str,
+    mut child: Child,
+    input: Option<Vec<u8>>,
+    timeout: Duration,
+    failure_level: log::Level,
+) -> Option<bool> {
+    let finished = async {
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            // Hooks that don't read their input may exit before it's all written
+            let _ = stdin.write_all(&input).await;
+        }
+        child.wait().await
+    };
+    match tokio::time::timeout(timeout, finished).await {
+        Ok(Ok(status)) if status.success() => Some(true),
+        Ok(Ok(status)) => {
+            log::log!(failure_level, "Hook command {} failed: {}", program, status);
+            Some(false)
+        }
+        Ok(Err(e)) => {
+            log::warn!("Failed to wait for hook command {}: {}", program, e);
+            Some(false)
+        }
+        Err(_) => {
+            log::warn!(
+                "Hook command {} timed out after {} seconds",
+                program,
+                timeout.as_secs()
+            );
+            None
+        }
+    }
+}
+
+/// Flatten `value` into environment variables, such as `SSH_AGENT_MUX_CLIENT_PID` for
+/// `{"client": {"pid": 1}}`
+fn env_vars(name: String, value: &serde_json::Value, env: &mut Env) {
+    use serde_json::Value;
+    match value {
+        Value::Null => (),
+        Value::String(s) => env.push((name, s.clone())),
+        Value::Object(fields) => {
+            let prefix = if name.ends_with('_') {
+                name
+            } else {
+                name + "_"
+            };
+            for (field, value) in fields {
+                env_vars(prefix.clone() + &field.to_uppercase(), value, env);
+            }
+        }
+        other => env.push((name, other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_details_in_env_vars() {
+        let event = Event::UpstreamOffline {
+            upstream: "/run/agent.sock".into(),
+            error: "refused".into(),
+        };
+        let mut env = vec![];
+        env_vars(
+            ENV_PREFIX.into(),
+            &serde_json::to_value(&event).unwrap(),
+            &mut env,
+        );
+        env.sort();
+        assert_eq!(
+            env,
+            [
+                ("SSH_AGENT_MUX_ERROR".into(), "refused".into()),
+                ("SSH_AGENT_MUX_EVENT".into(), "upstream-offline".into()),
+                ("SSH_AGENT_MUX_UPSTREAM".into(), "/run/agent.sock".into()),
+            ]
+        );
+
+        let record = SignRecord {
+            fingerprint: "SHA256:abc".into(),
+            latency_ms: 1.5,
+            client: crate::audit::ClientRecord {
+                pid: Some(42),
+                ..Default::default()
+            },
+            purpose: "sshsig",
+            ..Default::default()
+        };
+        let mut env = vec![];
+        env_vars(
+            ENV_PREFIX.into(),
+            &serde_json::to_value(Event::SignCompleted(record)).unwrap(),
+            &mut env,
+        );
+        assert!(env.contains(&("SSH_AGENT_MUX_CLIENT_PID".into(), "42".into())));
+        assert!(env.contains(&("SSH_AGENT_MUX_EVENT".into(), "sign-completed".into())));
+        assert!(env.contains(&("SSH_AGENT_MUX_LATENCY_MS".into(), "1.5".into())));
+        assert!(!env
+            .iter()
+            .any(|(name, _)| name == "SSH_AGENT_MUX_CLIENT_UID"));
+    }
+}
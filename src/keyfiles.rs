@@ -0,0 +1,255 @@
+//! Upstream signing with OpenSSH private key files, decrypting them with a passphrase from an
+//! askpass program
+
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::{
+    agent::Session,
+    error::AgentError,
+    proto::{Extension, Identity, PublicCredential, SignRequest},
+    ssh_key::{public::KeyData as PubKeyData, PrivateKey, Signature},
+};
+use tokio::{process::Command, sync::Mutex};
+
+use crate::signing;
+
+/// Suffixes of files that never hold a private key, skipped when expanding
+/// [`KeyFilesConfig::paths`]
+const SKIPPED_FILE_SUFFIXES: &[&str] = &[".pub", "known_hosts", "known_hosts.old", "config"];
+
+/// How long the askpass program may wait for a passphrase, before it's killed
+const ASKPASS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Settings for private keys loaded from files by the mux itself
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyFilesConfig {
+    /// OpenSSH private key files, directories containing them, or glob patterns such as
+    /// `~/.ssh/id_*`
+    ///
+    /// Files are re-read whenever clients list keys, so keys created later are picked up.
+    pub paths: Vec<PathBuf>,
+
+    /// Program that prompts for the passphrase of an encrypted key, like `SSH_ASKPASS`
+    ///
+    /// Run with the prompt as its only argument; it must print the passphrase on standard output.
+    /// Defaults to the `SSH_ASKPASS` environment variable.
+    pub askpass: Option<PathBuf>,
+
+    /// How long a decrypted key is kept in memory after the passphrase was entered, in seconds
+    ///
+    /// If not set, decrypted keys are kept until the configuration is reloaded.
+    pub decrypted_lifetime: Option<u64>,
+}
+
+struct DecryptedKey {
+    key: PrivateKey,
+    expires: Option<Instant>,
+}
+
+/// Read-only upstream signing with private keys loaded directly from files
+#[derive(Clone)]
+pub(crate) struct KeyFiles {
+    config: KeyFilesConfig,
+    decrypted: Arc<Mutex<HashMap<PubKeyData, DecryptedKey>>>,
+}
+
+impl KeyFiles {
+    pub fn new(config: KeyFilesConfig) -> Self {
+        Self {
+            config,
+            decrypted: Default::default(),
+        }
+    }
+
+    /// Load every private key file, keeping encrypted keys encrypted
+    fn load(&self) -> Vec<(PathBuf, PrivateKey)> {
+        let mut keys: Vec<(PathBuf, PrivateKey)> = vec![];
+        for path in self.config.paths.iter().flat_map(|p| key_files(p)) {
+            match PrivateKey::read_openssh_file(&path) {
+                Ok(key) => {
+                    if !keys.iter().any(|(_, k)| k.public_key() == key.public_key()) {
+                        keys.push((path, key));
+                    }
+                }
+                Err(e) => log::debug!("Ignoring {}: {}", path.display(), e),
+            }
+        }
+        keys
+    }
+
+    /// Get the decrypted private key for `path`, prompting for its passphrase if it isn't cached
+    async fn decrypt(&self, path: &Path, key: PrivateKey) -> Result<PrivateKey, AgentError> {
+        let pubkey = key.public_key().key_data().clone();
+        // Held while prompting, so concurrent requests for the same key only prompt once
+        let mut decrypted = self.decrypted.lock().await;
+        let now = Instant::now();
+        decrypted.retain(|_, k| k.expires.is_none_or(|expires| expires > now));
+        if let Some(cached) = decrypted.get(&pubkey) {
+            return Ok(cached.key.clone());
+        }
+
+        let passphrase = self
+            .askpass(&format!("Enter passphrase for {}: ", path.display()))
+            .await?;
+        let key = key.decrypt(passphrase).map_err(|e| {
+            log::error!("Failed to decrypt {}: {}", path.display(), e);
+            AgentError::Failure
+        })?;
+        log::info!(
+            "Decrypted {} key {} from {}",
+            key.algorithm(),
+            key.fingerprint(Default::default()),
+            path.display()
+        );
+        decrypted.insert(
+            pubkey,
+            DecryptedKey {
+                key: key.clone(),
+                expires: self
+                    .config
+                    .decrypted_lifetime
+                    .map(|secs| now + Duration::from_secs(secs)),
+            },
+        );
+        Ok(key)
+    }
+
+    async fn askpass(&self, prompt: &str) -> Result<String, AgentError> {
+        let Some(program) = self
+            .config
+            .askpass
+            .clone()
+            .or_else(|| env::var_os("SSH_ASKPASS").map(PathBuf::from))
+        else {
+            log::error!("No askpass program configured to decrypt private key files");
+            return Err(AgentError::Failure);
+        };
+
+        let output = Command::new(&program)
+            .arg(prompt)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .output();
+        let Ok(output) = tokio::time::timeout(ASKPASS_TIMEOUT, output).await else {
+            log::warn!(
+                "Askpass program {} timed out after {} seconds",
+                program.display(),
+                ASKPASS_TIMEOUT.as_secs()
+            );
+            return Err(AgentError::Failure);
+        };
+        let output = output?;
+        if !output.status.success() {
+            log::error!(
+                "Askpass program {} failed: {}",
+                program.display(),
+                output.status
+            );
+            return Err(AgentError::Failure);
+        }
+        let passphrase = String::from_utf8(output.stdout).map_err(AgentError::other)?;
+        Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+// Never print private keys, even in trace logs
+impl fmt::Debug for KeyFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyFiles")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for KeyFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("key files")?;
+        for (i, path) in self.config.paths.iter().enumerate() {
+            f.write_str(if i == 0 { " " } else { ", " })?;
+            path.display().fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for KeyFiles {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        Ok(self
+            .load()
+            .into_iter()
+            .map(|(path, key)| Identity {
+                credential: PublicCredential::Key(key.public_key().key_data().clone()),
+                comment: match key.comment() {
+                    "" => path.display().to_string(),
+                    comment => comment.to_string(),
+                },
+            })
+            .collect())
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        let pubkey = request.credential.key_data();
+        let Some((path, key)) = self
+            .load()
+            .into_iter()
+            .find(|(_, k)| k.public_key().key_data() == pubkey)
+        else {
+            return Err(AgentError::Failure);
+        };
+        let key = if key.is_encrypted() {
+            self.decrypt(&path, key).await?
+        } else {
+            key
+        };
+        signing::sign(&key, &request.data, request.flags)
+    }
+
+    async fn extension(&mut self, _extension: Extension) -> Result<Option<Extension>, AgentError> {
+        Err(AgentError::Failure)
+    }
+}
+
+/// Expand `path` to a list of candidate private key files: the files matching it as a glob
+/// pattern, with directories replaced by the files within
+fn key_files(path: &Path) -> Vec<PathBuf> {
+    let pattern = path.to_string_lossy();
+    let matches: Vec<PathBuf> = match glob::glob(&pattern) {
+        Ok(matches) => matches.filter_map(Result::ok).collect(),
+        Err(e) => {
+            log::warn!("Invalid key file pattern {}: {}", pattern, e);
+            return vec![];
+        }
+    };
+
+    let mut files = vec![];
+    for path in matches {
+        if path.is_dir() {
+            match fs::read_dir(&path) {
+                Ok(entries) => files.extend(entries.filter_map(|e| Some(e.ok()?.path()))),
+                Err(e) => log::warn!("Couldn't read key files from {}: {}", path.display(), e),
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    files.retain(|p| {
+        let skipped = p
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| SKIPPED_FILE_SUFFIXES.iter().any(|s| n.ends_with(s)));
+        !skipped && p.is_file()
+    });
+    files.sort();
+    files
+}
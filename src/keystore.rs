@@ -0,0 +1,225 @@
+//! The built-in keystore, holding keys added through the mux with `ssh-add`
+
+use std::{
+    fmt,
+    sync::{Arc, LazyLock, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::{
+    agent::Session,
+    error::AgentError,
+    proto::{
+        AddIdentity, AddIdentityConstrained, Extension, Identity, KeyConstraint, PrivateCredential,
+        PublicCredential, RemoveIdentity, SignRequest,
+    },
+    ssh_key::{Certificate, PrivateKey, Signature},
+};
+
+use crate::signing;
+
+/// Keys added to the built-in keystore are shared by every keystore upstream, so they outlive
+/// reloading the configuration
+static KEYS: LazyLock<Arc<Mutex<Vec<StoredKey>>>> = LazyLock::new(Default::default);
+
+/// Settings for the built-in keystore
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeystoreConfig {
+    /// Maximum lifetime of added keys, in seconds
+    ///
+    /// Applies to keys added without a lifetime, and shortens longer lifetimes requested with
+    /// `ssh-add -t`.
+    pub max_lifetime: Option<u64>,
+}
+
+struct StoredKey {
+    key: PrivateKey,
+    certificate: Option<Box<Certificate>>,
+    comment: String,
+    expires: Option<Instant>,
+}
+
+impl StoredKey {
+    fn credential(&self) -> PublicCredential {
+        match &self.certificate {
+            Some(cert) => PublicCredential::Cert(cert.clone()),
+            None => PublicCredential::Key(self.key.public_key().key_data().clone()),
+        }
+    }
+}
+
+/// In-memory keystore, holding keys added with `ssh-add` like OpenSSH `ssh-agent`
+#[derive(Clone)]
+pub(crate) struct Keystore {
+    keys: Arc<Mutex<Vec<StoredKey>>>,
+    config: KeystoreConfig,
+}
+
+impl Keystore {
+    pub fn new(config: KeystoreConfig) -> Self {
+        Self {
+            keys: KEYS.clone(),
+            config,
+        }
+    }
+
+    fn add(&self, credential: PrivateCredential, lifetime: Option<u64>) -> Result<(), AgentError> {
+        let (key, certificate, comment) = match credential {
+            PrivateCredential::Key { privkey, comment } => {
+                let key = PrivateKey::new(privkey, &comment).map_err(AgentError::other)?;
+                (key, None, comment)
+            }
+            PrivateCredential::Cert {
+                certificate,
+                privkey,
+                comment,
+                ..
+            } => {
+                let Some(keypair) = signing::keypair_from_parts(certificate.public_key(), privkey)
+                else {
+                    log::error!(
+                        "Private key doesn't match certificate {:?}",
+                        certificate.key_id()
+                    );
+                    return Err(AgentError::Failure);
+                };
+                let key = PrivateKey::new(keypair, &comment).map_err(AgentError::other)?;
+                (key, Some(certificate), comment)
+            }
+        };
+
+        let lifetime = match (lifetime, self.config.max_lifetime) {
+            (Some(requested), Some(max)) => Some(requested.min(max)),
+            (requested, max) => requested.or(max),
+        };
+        let stored = StoredKey {
+            key,
+            certificate,
+            comment,
+            expires: lifetime.map(|secs| Instant::now() + Duration::from_secs(secs)),
+        };
+        log::info!(
+            "Adding {} key {} to built-in keystore{}",
+            stored.key.algorithm(),
+            stored.key.fingerprint(Default::default()),
+            lifetime.map_or_else(String::new, |secs| format!(" for {secs} seconds"))
+        );
+
+        let mut keys = self.keys();
+        let credential = stored.credential();
+        keys.retain(|k| k.credential() != credential);
+        keys.push(stored);
+        Ok(())
+    }
+
+    /// Lock the stored keys, first removing any that have expired
+    fn keys(&self) -> MutexGuard<'_, Vec<StoredKey>> {
+        let mut keys = self.keys.lock().expect("keystore lock poisoned");
+        let now = Instant::now();
+        keys.retain(|k| {
+            let expired = k.expires.is_some_and(|expires| expires <= now);
+            if expired {
+                log::info!(
+                    "Removing expired key {} from built-in keystore",
+                    k.key.fingerprint(Default::default())
+                );
+            }
+            !expired
+        });
+        keys
+    }
+}
+
+// Never print private keys, even in trace logs
+impl fmt::Debug for Keystore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keystore")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+#[ssh_agent_lib::async_trait]
+impl Session for Keystore {
+    async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
+        Ok(self
+            .keys()
+            .iter()
+            .map(|k| Identity {
+                credential: k.credential(),
+                comment: k.comment.clone(),
+            })
+            .collect())
+    }
+
+    async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
+        let keys = self.keys();
+        let pubkey = request.credential.key_data();
+        let Some(stored) = keys
+            .iter()
+            .find(|k| k.key.public_key().key_data() == pubkey)
+        else {
+            return Err(AgentError::Failure);
+        };
+        signing::sign(&stored.key, &request.data, request.flags)
+    }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        self.add(identity.credential, None)
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        let mut lifetime = None;
+        for constraint in identity.constraints {
+            match constraint {
+                KeyConstraint::Lifetime(secs) => lifetime = Some(secs.into()),
+                // Neither confirmation nor destination restrictions can be enforced, so refuse
+                // to add the key rather than silently ignoring the constraint
+                KeyConstraint::Confirm => {
+                    log::error!("Built-in keystore doesn't support confirmation constraints");
+                    return Err(AgentError::Failure);
+                }
+                KeyConstraint::Extension(ext) => {
+                    log::error!(
+                        "Built-in keystore doesn't support constraint extension {}",
+                        ext.name
+                    );
+                    return Err(AgentError::Failure);
+                }
+            }
+        }
+        self.add(identity.identity.credential, lifetime)
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        let mut keys = self.keys();
+        let before = keys.len();
+        keys.retain(|k| k.credential() != identity.credential);
+        if keys.len() == before {
+            return Err(AgentError::Failure);
+        }
+        log::info!(
+            "Removed key {} from built-in keystore",
+            identity
+                .credential
+                .key_data()
+                .fingerprint(Default::default())
+        );
+        Ok(())
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        log::info!("Removing all keys from built-in keystore");
+        self.keys().clear();
+        Ok(())
+    }
+
+    async fn extension(&mut self, _extension: Extension) -> Result<Option<Extension>, AgentError> {
+        Err(AgentError::Failure)
+    }
+}
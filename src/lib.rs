@@ -1,55 +1,159 @@
 use std::{
-    collections::HashMap,
-    os::unix::net::UnixStream,
+    collections::{HashMap, HashSet},
+    os::unix::fs::FileTypeExt,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use rsa::signature::Verifier;
 use ssh_agent_lib::{
     agent::{self, Agent, ListeningSocket, Session},
-    client,
     error::AgentError,
-    proto::{extension::QueryResponse, Extension, Identity, SignRequest},
+    proto::{
+        extension::{QueryResponse, SessionBind},
+        AddIdentity, AddIdentityConstrained, Extension, Identity, PublicCredential, RemoveIdentity,
+        SignRequest,
+    },
     ssh_key::{public::KeyData as PubKeyData, Signature},
 };
 use tokio::{
     net::UnixListener,
-    sync::{Mutex, OwnedMutexGuard},
+    sync::{Mutex, MutexGuard, OwnedMutexGuard},
 };
 
-type KnownPubKeysMap = HashMap<PubKeyData, PathBuf>;
+mod address;
+mod algorithms;
+mod audit;
+mod availability;
+mod certs;
+mod clients;
+mod control;
+mod discovery;
+mod exec;
+mod health;
+mod hooks;
+mod keyfiles;
+mod keystore;
+mod lock;
+mod path_command;
+mod purpose;
+mod ratelimit;
+mod rules;
+mod signing;
+mod upstream;
+mod verify;
+mod watch;
+
+pub use address::{AddressParseError, UpstreamAddress};
+pub use algorithms::{AlgorithmPolicy, Sha1Rsa};
+use audit::{AuditLog, SignRecord};
+use availability::KeyAppearances;
+pub use availability::{Availability, Day};
+use certs::AttachedCertificates;
+pub use certs::{AttachMode, CertificateConfig};
+use clients::ClientProcess;
+pub use clients::{AllowedClients, ClientPolicy};
+pub use control::{
+    control_path, control_request, ControlEvent, ControlEvents, ControlRequest, ControlResponse,
+    KeyStatus, Status, UpstreamStatus,
+};
+pub use discovery::{GroupConfig, GroupStrategy};
+use health::HealthTracker;
+use hooks::Event;
+pub use hooks::{Hook, HookEvent};
+pub use keyfiles::KeyFilesConfig;
+use keystore::Keystore;
+pub use keystore::KeystoreConfig;
+pub use lock::LockConfig;
+use lock::LockState;
+use purpose::Purpose;
+pub use purpose::PurposePolicy;
+use ratelimit::RateLimiter;
+pub use ratelimit::{ClientBy, RateLimit, RateLimits};
+pub use rules::{explain, Hours, PurposeKind, Rule, RuleAction, RuleRequest};
+use upstream::Upstream;
+pub use upstream::UpstreamConfig;
+use verify::Verify;
+use watch::SocketWatcher;
+
+/// A key offered by an upstream agent
+#[derive(Clone, Debug)]
+struct KnownKey {
+    upstream: Upstream,
+    comment: String,
+    /// When the key first appeared from its upstream
+    since: Instant,
+}
+
+type KnownPubKeysMap = HashMap<PubKeyData, KnownKey>;
 type KnownPubKeys = Arc<Mutex<KnownPubKeysMap>>;
 
-/// Only the `request_identities`, `sign`, and `extension` commands are implemented. For
+/// Only the `request_identities`, `sign`, and `extension` commands are implemented, plus adding
+/// and removing identities when the built-in keystore is configured as an upstream. For
 /// `extension`, only the `session-bind@openssh.com` and `query` extensions are supported.
 #[ssh_agent_lib::async_trait]
 impl Session for MuxAgent {
     async fn request_identities(&mut self) -> Result<Vec<Identity>, AgentError> {
         log::trace!("incoming: request_identities");
+        if self.is_locked().await && !self.options.lock.list_while_locked {
+            log::debug!("Offering no keys to {} while locked", self.client);
+            return Ok(vec![]);
+        }
         let mut known_keys = self.known_keys.clone().lock_owned().await;
-        self.refresh_identities(&mut known_keys).await
+        let mut identities = self.refresh_identities(&mut known_keys).await?;
+        if let Some(policy) = &self.client_policy {
+            let count = identities.len();
+            identities.retain(|id| {
+                let key = id.credential.key_data();
+                known_keys
+                    .get(key)
+                    .is_some_and(|known| policy.allows(key, &known.upstream.to_string()))
+            });
+            log::debug!(
+                "Offering {} of {} keys to {}, by its client policy",
+                identities.len(),
+                count,
+                self.client
+            );
+        }
+        if !self.options.rules.is_empty() {
+            identities.retain(|id| {
+                let key = id.credential.key_data();
+                let upstream = known_keys.get(key).map(|known| &known.upstream);
+                rules::evaluate(&self.options.rules, &self.rule_request(key, upstream)).action
+                    != RuleAction::Hide
+            });
+        }
+        if !self.options.availability.is_empty() {
+            identities.retain(|id| {
+                let key = id.credential.key_data();
+                known_keys
+                    .get(key)
+                    .is_none_or(|known| self.is_available(key, known))
+            });
+        }
+        Ok(identities)
     }
 
     async fn sign(&mut self, request: SignRequest) -> Result<Signature, AgentError> {
-        let fingerprint = request.pubkey.fingerprint(Default::default());
-        log::trace!("incoming: sign({})", &fingerprint);
-
-        if let Some(agent_sock_path) = self.get_agent_sock_for_pubkey(&request.pubkey).await? {
-            log::info!(
-                "Requesting signature with key {} from upstream agent <{}>",
-                &fingerprint,
-                agent_sock_path.display()
-            );
-
-            let mut client = self.connect_upstream_agent(agent_sock_path)?;
-            client.sign(request).await
-        } else {
-            log::error!("No upstream agent found for public key {}", &fingerprint);
-            log::trace!("Known keys:\n{:#?}", self.known_keys);
-            Err(AgentError::Other(
-                format!("No agent found for public key: {}", &fingerprint).into(),
-            ))
+        let started = Instant::now();
+        let purpose = Purpose::parse(&request.data);
+        let mut record = SignRecord::new(&purpose, &self.client, self.bound_host_key.as_ref());
+        let result = self.sign_request(request, &purpose, &mut record).await;
+        record.finish(&result, started.elapsed());
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.write(&record);
         }
+        self.notify(if result.is_ok() {
+            Event::SignCompleted(record)
+        } else {
+            Event::SignFailed(record)
+        });
+        result
     }
 
     async fn extension(&mut self, request: Extension) -> Result<Option<Extension>, AgentError> {
@@ -59,31 +163,62 @@ impl Session for MuxAgent {
                 extensions: ["session-bind@openssh.com"].map(String::from).to_vec(),
             })?)),
             "session-bind@openssh.com" => {
+                let Ok(Some(bind)) = request.parse_message::<SessionBind>() else {
+                    log::warn!(
+                        "Invalid session-bind@openssh.com request from {}",
+                        self.client
+                    );
+                    return Err(AgentError::Failure);
+                };
+                let host_key = bind.host_key.fingerprint(Default::default());
+                if let Err(e) = bind.host_key.verify(&bind.session_id, &bind.signature) {
+                    log::warn!(
+                        "Refusing session-bind@openssh.com from {}: bad signature by host key {}: {}",
+                        self.client,
+                        host_key,
+                        e
+                    );
+                    return Err(AgentError::Failure);
+                }
+                // Like OpenSSH's agent, don't bind a connection again after binding it for
+                // authentication; and unlike it, not for another hop of a forwarded connection
+                // either, which would let the remote host choose which host key rules see
+                if self.bound_host_key.is_some() {
+                    log::warn!(
+                        "Refusing session-bind@openssh.com from {} to host key {}: already bound",
+                        self.client,
+                        host_key
+                    );
+                    return Err(AgentError::Failure);
+                }
                 let mut session_bind_suceeded = false;
-                for sock_path in &self.socket_paths {
+                for upstream in &self.upstreams() {
                     // Try extension on upstream agents; discard any upstream failures from agents
                     // that don't support the extension (but the default is Failure if there are no
                     // successful upstream responses)
-                    if let Ok(mut client) = self.connect_upstream_agent(sock_path) {
+                    if let Ok(mut client) = upstream.connect(self.verify()).await {
                         match client.extension(request.clone()).await {
                             // Any agent succeeding is an overall success
                             Ok(v) => {
                                 session_bind_suceeded = true;
                                 if v.is_some() {
-                                    log::warn!("session-bind@openssh.com request succeeded on socket <{}>, but an invalid response was received", sock_path.display());
+                                    log::warn!("session-bind@openssh.com request succeeded on socket <{}>, but an invalid response was received", upstream);
                                 }
                             }
                             // Don't propagate upstream lack of extension support
                             Err(AgentError::Failure) => continue,
                             // Report but ignore any unexpected errors
                             Err(e) => {
-                                log::error!("Unexpected error on socket <{}> when requesting session-bind@openssh.com extension: {}", sock_path.display(), e);
+                                log::error!("Unexpected error on socket <{}> when requesting session-bind@openssh.com extension: {}", upstream, e);
                                 continue;
                             }
                         }
                     }
                 }
                 if session_bind_suceeded {
+                    log::debug!("{} bound to host key {}", self.client, host_key);
+                    self.bound_host_key = Some(bind.host_key);
+                    self.forwarded = bind.is_forwarding;
                     Ok(None)
                 } else {
                     Err(AgentError::Failure)
@@ -92,85 +227,616 @@ impl Session for MuxAgent {
             _ => Err(AgentError::Failure),
         }
     }
+
+    async fn add_identity(&mut self, identity: AddIdentity) -> Result<(), AgentError> {
+        log::trace!("incoming: add_identity");
+        self.refuse_if_locked().await?;
+        self.keystore()?.add_identity(identity).await
+    }
+
+    async fn add_identity_constrained(
+        &mut self,
+        identity: AddIdentityConstrained,
+    ) -> Result<(), AgentError> {
+        log::trace!("incoming: add_identity_constrained");
+        self.refuse_if_locked().await?;
+        self.keystore()?.add_identity_constrained(identity).await
+    }
+
+    async fn remove_identity(&mut self, identity: RemoveIdentity) -> Result<(), AgentError> {
+        log::trace!("incoming: remove_identity");
+        self.refuse_if_locked().await?;
+        self.keystore()?.remove_identity(identity).await
+    }
+
+    async fn remove_all_identities(&mut self) -> Result<(), AgentError> {
+        log::trace!("incoming: remove_all_identities");
+        self.refuse_if_locked().await?;
+        self.keystore()?.remove_all_identities().await
+    }
+
+    async fn lock(&mut self, passphrase: String) -> Result<(), AgentError> {
+        log::trace!("incoming: lock");
+        if self.lock_state.lock().await.lock(passphrase) {
+            log::info!("Locked by {}", self.client);
+            Ok(())
+        } else {
+            Err(AgentError::Failure)
+        }
+    }
+
+    async fn unlock(&mut self, passphrase: String) -> Result<(), AgentError> {
+        log::trace!("incoming: unlock");
+        if self
+            .unlock_with(&passphrase, Some(&self.client.to_string()))
+            .await
+        {
+            log::info!("Unlocked by {}", self.client);
+            Ok(())
+        } else {
+            log::warn!("Failed attempt to unlock by {}", self.client);
+            Err(AgentError::Failure)
+        }
+    }
+}
+
+/// Optional behaviour of a [`MuxAgent`], in addition to the upstream agents it forwards to
+#[derive(Clone, Debug, Default)]
+pub struct MuxOptions {
+    pub certificates: CertificateConfig,
+    /// Also check the user that upstream agents run as, not only who owns their sockets
+    pub verify_peer_credentials: bool,
+    /// Other users and groups allowed to connect to the mux
+    pub allowed_clients: AllowedClients,
+    /// Keys that each client program may use
+    pub client_policies: Vec<ClientPolicy>,
+    /// What each key may be used to sign
+    pub purpose_policies: Vec<PurposePolicy>,
+    /// Minimum acceptable signature algorithms and key types
+    pub algorithms: AlgorithmPolicy,
+    /// File to append a JSON record of each signing request to
+    pub audit_log: Option<PathBuf>,
+    /// How fast clients may ask for signatures
+    pub rate_limits: RateLimits,
+    /// Commands to run when something happens, such as a key being asked to sign
+    pub hooks: Vec<Hook>,
+    /// Ordered rules deciding which keys are offered, and what they may sign
+    pub rules: Vec<Rule>,
+    /// Windows of time in which keys are available
+    pub availability: Vec<Availability>,
+    /// When the mux locks itself
+    pub lock: LockConfig,
+    /// Also listen on a control socket, next to the listen socket, for administrative requests
+    pub control_socket: bool,
+    /// Where to send control requests that the program running the mux must carry out, such as
+    /// reloading its configuration; without it, those requests fail
+    pub control_events: Option<ControlEvents>,
+    /// State to keep when the mux is run again to reload its configuration
+    pub state: MuxState,
+}
+
+/// State of a [`MuxAgent`] that outlives it, so that a mux run again with the same state stays
+/// locked, and keeps its disabled upstream agents, their health, rate limits, and when keys
+/// appeared
+#[derive(Clone, Debug, Default)]
+pub struct MuxState {
+    health: Arc<Mutex<HealthTracker>>,
+    key_appearances: Arc<Mutex<KeyAppearances>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    lock_state: Arc<Mutex<LockState>>,
+    disabled: Arc<std::sync::Mutex<HashSet<String>>>,
 }
 
 #[derive(Clone)]
 pub struct MuxAgent {
-    socket_paths: Vec<PathBuf>,
+    upstreams: Vec<Upstream>,
+    listen_path: PathBuf,
     known_keys: KnownPubKeys,
+    // Set when upstream sockets appear or disappear, so known_keys must be refreshed
+    stale: Arc<AtomicBool>,
+    // Set after keys are first listed, so that keys listed later are known to be new
+    listed_once: Arc<AtomicBool>,
+    // Every key listed since the mux started, so that keys of an upstream agent that was
+    // failing for a while aren't taken for new ones when it's back
+    seen_keys: Arc<std::sync::Mutex<HashSet<PubKeyData>>>,
+    health: Arc<Mutex<HealthTracker>>,
+    key_appearances: Arc<Mutex<KeyAppearances>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    lock_state: Arc<Mutex<LockState>>,
+    // Names of upstream agents disabled through the control socket
+    disabled: Arc<std::sync::Mutex<HashSet<String>>>,
+    attached_certs: Arc<Mutex<AttachedCertificates>>,
+    options: MuxOptions,
+    // The client of this session, and the policy chosen for it when it connected
+    client: ClientProcess,
+    client_policy: Option<ClientPolicy>,
+    // Host key of the server the client is connected to, from session-bind@openssh.com, only
+    // once its signature is verified, since rules and audit records trust it
+    bound_host_key: Option<PubKeyData>,
+    // Whether this session is a forwarded agent connection, from session-bind@openssh.com
+    forwarded: bool,
+    audit_log: Option<AuditLog>,
 }
 
 impl MuxAgent {
     /// Run a MuxAgent, listening for SSH agent protocol requests on `listen_sock`, forwarding
-    /// requests to the specified paths in `agent_socks`
-    pub async fn run<I, P>(listen_sock: impl AsRef<Path>, agent_socks: I) -> Result<(), AgentError>
+    /// requests to the upstream agents specified in `agent_socks`
+    pub async fn run<I, U>(
+        listen_sock: impl AsRef<Path>,
+        agent_socks: I,
+        options: MuxOptions,
+    ) -> Result<(), AgentError>
     where
-        I: IntoIterator<Item = P>,
-        P: AsRef<Path>,
+        I: IntoIterator<Item = U>,
+        U: Into<UpstreamConfig>,
     {
         let listen_sock = listen_sock.as_ref();
-        let socket_paths: Vec<_> = agent_socks
+        let mut upstreams: Vec<_> = agent_socks
             .into_iter()
-            .map(|p| p.as_ref().to_path_buf())
+            .map(|u| Upstream::new(u.into()))
             .collect();
-        if socket_paths.is_empty() {
+        let mut found_keystore = false;
+        upstreams.retain(|u| match u {
+            Upstream::Keystore(_) if found_keystore => {
+                log::warn!("Ignoring duplicate built-in keystore upstream");
+                false
+            }
+            Upstream::Keystore(_) => {
+                found_keystore = true;
+                true
+            }
+            _ => true,
+        });
+        if upstreams.is_empty() {
             log::warn!("Mux agent running but no upstream agents configured");
         }
         log::info!(
             "Starting agent for {} upstream agents; listening on <{}>",
-            socket_paths.len(),
+            upstreams.len(),
             listen_sock.display()
         );
-        log::debug!("Upstream agent sockets: {:?}", &socket_paths);
+        log::debug!("Upstream agents: {:?}", &upstreams);
 
-        let listen_sock = match SelfDeletingUnixListener::bind(listen_sock) {
-            Ok(s) => s,
-            err => {
+        // Bound first, so that it's ready once the listen socket is
+        let control = if options.control_socket {
+            let path = control_path(listen_sock);
+            if path == listen_sock {
                 log::error!(
-                    "Failed to open listening socket at {}",
-                    listen_sock.display()
+                    "Control socket would replace the listen socket at {}",
+                    path.display()
                 );
-                err?
+                return Err(AgentError::Other(
+                    "Listen socket path must not end in .ctl when control_socket is enabled".into(),
+                ));
             }
+            match SelfDeletingUnixListener::bind(&path, AllowedClients::default()) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    log::error!("Failed to open control socket at {}", path.display());
+                    return Err(e.into());
+                }
+            }
+        } else {
+            None
         };
+        let listener =
+            match SelfDeletingUnixListener::bind(listen_sock, options.allowed_clients.clone()) {
+                Ok(s) => s,
+                err => {
+                    log::error!(
+                        "Failed to open listening socket at {}",
+                        listen_sock.display()
+                    );
+                    err?
+                }
+            };
+        let audit_log = match &options.audit_log {
+            Some(path) => match AuditLog::open(path) {
+                Ok(audit_log) => Some(audit_log),
+                Err(e) => {
+                    log::error!("Failed to open audit log {}: {}", path.display(), e);
+                    return Err(e.into());
+                }
+            },
+            None => None,
+        };
+        let stale = Arc::new(AtomicBool::new(false));
+        let _watcher = SocketWatcher::start(
+            upstreams
+                .iter()
+                .flat_map(Upstream::watch_patterns)
+                .collect(),
+            stale.clone(),
+        );
         let this = Self {
-            socket_paths,
+            upstreams,
+            listen_path: listen_sock.to_path_buf(),
             known_keys: Default::default(),
+            stale,
+            listed_once: Default::default(),
+            seen_keys: Default::default(),
+            health: options.state.health.clone(),
+            key_appearances: options.state.key_appearances.clone(),
+            rate_limiter: options.state.rate_limiter.clone(),
+            lock_state: options.state.lock_state.clone(),
+            disabled: options.state.disabled.clone(),
+            attached_certs: Arc::new(Mutex::new(AttachedCertificates::new(
+                options.certificates.paths.clone(),
+            ))),
+            options,
+            client: Default::default(),
+            client_policy: None,
+            bound_host_key: None,
+            forwarded: false,
+            audit_log,
         };
-        agent::listen(listen_sock, this).await
+        match control {
+            Some(control) => {
+                let control_agent = this.clone();
+                tokio::select! {
+                    res = agent::listen(listener, this) => res,
+                    res = control_agent.serve_control(control) => res,
+                }
+            }
+            None => agent::listen(listener, this).await,
+        }
     }
 
-    fn connect_upstream_agent(
+    /// Apply the mux's policies to a signing request, and forward it to the upstream holding the
+    /// key, filling in `record` along the way
+    async fn sign_request(
+        &mut self,
+        mut request: SignRequest,
+        purpose: &Purpose,
+        record: &mut SignRecord,
+    ) -> Result<Signature, AgentError> {
+        // Upstream agents don't know about certificates attached from disk, so ask them to sign
+        // with the underlying key instead
+        if let PublicCredential::Cert(cert) = &request.credential {
+            if self.attached_certs.lock().await.contains(cert) {
+                log::debug!(
+                    "Signing with key of attached certificate {:?}",
+                    cert.key_id()
+                );
+                request.credential = PublicCredential::Key(cert.public_key().clone());
+            }
+        }
+
+        let pubkey = request.credential.key_data();
+        let fingerprint = pubkey.fingerprint(Default::default());
+        log::trace!("incoming: sign({})", &fingerprint);
+        record.fingerprint = fingerprint.to_string();
+
+        if self.is_locked().await {
+            log::warn!(
+                "Refusing to sign with key {} for {}: locked",
+                &fingerprint,
+                self.client
+            );
+            record.refuse("locked");
+            return Err(AgentError::Failure);
+        }
+        self.lock_state.lock().await.record_activity(Instant::now());
+
+        let rate_limits = &self.options.rate_limits;
+        let limited =
+            self.rate_limiter
+                .lock()
+                .await
+                .check(rate_limits, &self.client, pubkey, Instant::now());
+        if let Err(violation) = limited {
+            log::warn!(
+                "Refusing to sign with key {} for {}: {}",
+                &fingerprint,
+                self.client,
+                violation
+            );
+            if violation.first {
+                ratelimit::run_hook(&rate_limits.on_limit, &violation, &self.client);
+            }
+            record.refuse("rate limited");
+            return Err(AgentError::Failure);
+        }
+
+        let algorithms = &self.options.algorithms;
+        if !algorithms.allows_key(pubkey) {
+            log::warn!(
+                "Refusing to sign with {} key {}: key type not allowed",
+                pubkey.algorithm(),
+                &fingerprint
+            );
+            record.refuse("key type not allowed");
+            return Err(AgentError::Failure);
+        }
+        let Some(flags) = algorithms.sign_flags(pubkey, request.flags) else {
+            log::warn!(
+                "Refusing to make a SHA-1 (ssh-rsa) signature with key {}",
+                &fingerprint
+            );
+            record.refuse("SHA-1 signature");
+            return Err(AgentError::Failure);
+        };
+        if flags != request.flags {
+            log::info!(
+                "Upgrading request for a SHA-1 (ssh-rsa) signature with key {} to rsa-sha2-512",
+                &fingerprint
+            );
+        }
+
+        if let Some(known) = self.get_upstream_for_pubkey(pubkey).await? {
+            let available = self.is_available(pubkey, &known);
+            let KnownKey {
+                upstream, comment, ..
+            } = known;
+            record.comment = Some(comment.clone());
+            record.upstream = Some(upstream.to_string());
+            if !available {
+                log::warn!(
+                    "Refusing to sign with key {}: outside its availability window",
+                    &fingerprint
+                );
+                record.refuse("outside availability window");
+                return Err(AgentError::Failure);
+            }
+            if let Some(policy) = &self.client_policy {
+                if !policy.allows(pubkey, &upstream.to_string()) {
+                    log::warn!(
+                        "Refusing to sign with key {} for {}: not allowed by its client policy",
+                        &fingerprint,
+                        self.client
+                    );
+                    record.refuse("not allowed by client policy");
+                    return Err(AgentError::Failure);
+                }
+            }
+            log::debug!("Request to sign {} with key {}", purpose, &fingerprint);
+            if let Some(policy) = PurposePolicy::select(&self.options.purpose_policies, pubkey) {
+                if !policy.allows(purpose) {
+                    log::warn!(
+                        "Refusing to sign {} with key {}: not allowed by its purpose policy",
+                        purpose,
+                        &fingerprint
+                    );
+                    record.refuse("not allowed by purpose policy");
+                    return Err(AgentError::Failure);
+                }
+            }
+            let mut rule_request = self.rule_request(pubkey, Some(&upstream));
+            rule_request.set_purpose(purpose);
+            match rules::evaluate(&self.options.rules, &rule_request).action {
+                RuleAction::Allow | RuleAction::Log => (),
+                RuleAction::Deny | RuleAction::Hide => {
+                    log::warn!(
+                        "Refusing to sign {} with key {} for {}: denied by rule",
+                        purpose,
+                        &fingerprint,
+                        self.client
+                    );
+                    record.refuse("denied by rule");
+                    return Err(AgentError::Failure);
+                }
+                RuleAction::Confirm => {
+                    let prompt = format!(
+                        "Allow {} to use key {} ({}) for {}?",
+                        self.client, &fingerprint, comment, purpose
+                    );
+                    if !rules::confirm(&prompt).await {
+                        log::warn!(
+                            "Refusing to sign {} with key {} for {}: not confirmed",
+                            purpose,
+                            &fingerprint,
+                            self.client
+                        );
+                        record.refuse("not confirmed");
+                        return Err(AgentError::Failure);
+                    }
+                }
+            }
+            log::info!(
+                "Requesting signature with key {} from upstream agent <{}>",
+                &fingerprint,
+                upstream
+            );
+
+            let event = Event::SignRequested(record.clone());
+            if !hooks::authorize(&self.options.hooks, &event).await {
+                log::warn!(
+                    "Refusing to sign with key {} for {}: denied by gating hook",
+                    &fingerprint,
+                    self.client
+                );
+                record.refuse("denied by gating hook");
+                return Err(AgentError::Failure);
+            }
+            self.notify(event);
+
+            request.flags = flags;
+            let result = match upstream.connect(self.verify()).await {
+                Ok(mut client) => client.sign(request).await,
+                Err(e) => Err(e),
+            };
+            let mut health = self.health.lock().await;
+            match &result {
+                Ok(_) => self.record_success(&mut health, &upstream, None),
+                // Refusals by the upstream agent don't mean it's unhealthy
+                Err(AgentError::Failure | AgentError::ExtensionFailure) => (),
+                Err(e) => self.record_failure(&mut health, &upstream, e),
+            }
+            result
+        } else {
+            log::error!("No upstream agent found for public key {}", &fingerprint);
+            log::trace!("Known keys:\n{:#?}", self.known_keys);
+            Err(AgentError::Other(
+                format!("No agent found for public key: {}", &fingerprint).into(),
+            ))
+        }
+    }
+
+    async fn is_locked(&self) -> bool {
+        self.lock_state
+            .lock()
+            .await
+            .is_locked(&self.options.lock, Instant::now())
+    }
+
+    /// Unlock with `passphrase`, or if the mux locked itself, once the user confirms that `by`
+    /// may unlock it, with the `SSH_ASKPASS` program
+    ///
+    /// `by` is `None` for the control socket, which only the user can connect to, so it needs no
+    /// confirmation, and unlocks hosts without a display.
+    async fn unlock_with(&self, passphrase: &str, by: Option<&str>) -> bool {
+        let now = Instant::now();
+        let by = {
+            let mut lock_state = self.lock_state.lock().await;
+            // Lock first if it's due, so that it's known whether the mux locked itself
+            lock_state.is_locked(&self.options.lock, now);
+            if !lock_state.is_auto_locked() {
+                return lock_state.unlock(passphrase, now);
+            }
+            match by {
+                Some(by) => by,
+                None => return lock_state.unlock_confirmed(now),
+            }
+        };
+        // Not holding the lock state while waiting, so that other clients aren't held up
+        let prompt = format!("Allow {} to unlock {}?", by, env!("CARGO_PKG_NAME"));
+        if !rules::confirm(&prompt).await {
+            log::warn!(
+                "Unlocking after locking automatically wasn't confirmed; `{} unlock` needs no confirmation",
+                env!("CARGO_PKG_NAME")
+            );
+            return false;
+        }
+        self.lock_state
+            .lock()
+            .await
+            .unlock_confirmed(Instant::now())
+    }
+
+    /// OpenSSH's agent refuses to change keys while locked, too
+    async fn refuse_if_locked(&self) -> Result<(), AgentError> {
+        if self.is_locked().await {
+            Err(AgentError::Failure)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn is_available(&self, key: &PubKeyData, known: &KnownKey) -> bool {
+        availability::is_available(
+            &self.options.availability,
+            key,
+            &known.upstream.to_string(),
+            known.since,
+        )
+    }
+
+    /// What rules can match on, for listing or signing with `key`
+    fn rule_request(&self, key: &PubKeyData, upstream: Option<&Upstream>) -> RuleRequest {
+        let fingerprint = |key: &PubKeyData| key.fingerprint(Default::default()).to_string();
+        RuleRequest {
+            key: Some(fingerprint(key)),
+            upstream: upstream.map(Upstream::to_string),
+            programs: self.client.exes(),
+            uid: self.client.credentials().map(|c| c.uid),
+            host_key: self.bound_host_key.as_ref().map(fingerprint),
+            forwarded: self.forwarded,
+            ..Default::default()
+        }
+    }
+
+    fn notify(&self, event: Event) {
+        hooks::notify(&self.options.hooks, &event);
+    }
+
+    fn record_success(
         &self,
-        sock_path: impl AsRef<Path>,
-    ) -> Result<Box<dyn Session>, AgentError> {
-        let sock_path = sock_path.as_ref();
-        let stream = UnixStream::connect(sock_path)?;
-        let client = client::connect(stream.into()).map_err(|e| {
-            AgentError::Other(
-                format!(
-                    "Failed to connect to agent at {}: {}",
-                    sock_path.display(),
-                    e
-                )
-                .into(),
-            )
-        })?;
-        log::trace!(
-            "Connected to upstream agent on socket: {}",
-            sock_path.display()
-        );
-        Ok(client)
+        health: &mut HealthTracker,
+        upstream: &Upstream,
+        latency: Option<Duration>,
+    ) {
+        let name = upstream.to_string();
+        if health.record_success(&name, latency) {
+            self.notify(Event::UpstreamOnline { upstream: name });
+        }
     }
 
-    async fn get_agent_sock_for_pubkey(
+    fn record_failure(&self, health: &mut HealthTracker, upstream: &Upstream, error: &AgentError) {
+        let name = upstream.to_string();
+        if health.record_failure(&name, error, Instant::now()) {
+            self.notify(Event::UpstreamOffline {
+                upstream: name,
+                error: error.to_string(),
+            });
+        }
+    }
+
+    /// Checks to make on upstream agent sockets before connecting
+    fn verify(&self) -> Verify {
+        if self.options.verify_peer_credentials {
+            Verify::PeerCredentials
+        } else {
+            Verify::Ownership
+        }
+    }
+
+    /// Enabled upstream agents in configured order, with globs expanded to the sockets they match
+    /// now
+    fn upstreams(&self) -> Vec<Upstream> {
+        self.all_upstreams()
+            .into_iter()
+            .filter_map(|(upstream, enabled)| enabled.then_some(upstream))
+            .collect()
+    }
+
+    /// Every upstream agent, expanded as for [`Self::upstreams`], and whether it's enabled
+    ///
+    /// An upstream is disabled if it, or the glob or group it was expanded from, was disabled
+    /// through the control socket.
+    fn all_upstreams(&self) -> Vec<(Upstream, bool)> {
+        let disabled = self
+            .disabled
+            .lock()
+            .expect("disabled upstreams lock poisoned");
+        let disabled = &*disabled;
+        self.upstreams
+            .iter()
+            .flat_map(|configured| {
+                let configured_enabled = !disabled.contains(&configured.to_string());
+                configured
+                    .expand(&self.listen_path)
+                    .into_iter()
+                    .map(move |upstream| {
+                        let enabled =
+                            configured_enabled && !disabled.contains(&upstream.to_string());
+                        (upstream, enabled)
+                    })
+            })
+            .collect()
+    }
+
+    /// The built-in keystore, which receives keys added through the mux with `ssh-add`
+    fn keystore(&self) -> Result<Keystore, AgentError> {
+        match self.upstreams.iter().find_map(Upstream::as_keystore) {
+            Some(keystore) => Ok(keystore.clone()),
+            None => {
+                log::error!("Can't add or remove keys: no built-in keystore upstream configured");
+                Err(AgentError::Failure)
+            }
+        }
+    }
+
+    async fn get_upstream_for_pubkey(
         &mut self,
         pubkey: &PubKeyData,
-    ) -> Result<Option<PathBuf>, AgentError> {
+    ) -> Result<Option<KnownKey>, AgentError> {
         // Refresh available identities if the public key isn't found;
         // hold lock for duration of signing operation
         let mut known_keys = self.known_keys.clone().lock_owned().await;
-        if !known_keys.contains_key(pubkey) {
+        if self.stale.load(Ordering::Relaxed) {
+            log::debug!("Upstream agents changed, re-requesting keys from upstream agents");
+            let _ = self.refresh_identities(&mut known_keys).await?;
+        } else if !known_keys.contains_key(pubkey) {
             log::debug!("Key not found, re-requesting keys from upstream agents");
             let _ = self.refresh_identities(&mut known_keys).await?;
         }
@@ -186,44 +852,193 @@ impl MuxAgent {
     ) -> Result<Vec<Identity>, AgentError> {
         let mut identities = vec![];
         known_keys.clear();
+        let stale = self.stale.swap(false, Ordering::Relaxed);
 
         log::debug!("Refreshing identities");
-        for sock_path in &self.socket_paths {
-            let mut client = match self.connect_upstream_agent(sock_path) {
-                Ok(c) => c,
-                Err(_) => {
-                    log::warn!(
-                        "Ignoring missing upstream agent socket: {}",
-                        sock_path.display()
-                    );
-                    continue;
+        let mut attached_certs = self.attached_certs.lock().await;
+        attached_certs.reload_if_changed();
+        let mut health = self.health.lock().await;
+        if stale {
+            // Sockets have changed, so agents that were failing may be back
+            health.reset_backoff();
+        }
+        let upstreams = self.upstreams();
+        health.retain(&upstreams.iter().map(|u| u.to_string()).collect::<Vec<_>>());
+        let mut key_appearances = self.key_appearances.lock().await;
+        // Keys of disabled upstreams keep their times, for when they're enabled again
+        key_appearances.retain(
+            &self
+                .all_upstreams()
+                .iter()
+                .map(|(u, _)| u.to_string())
+                .collect::<Vec<_>>(),
+        );
+        for upstream in &upstreams {
+            let name = upstream.to_string();
+            if health.should_skip(&name, Instant::now()) {
+                if let Some(h) = health.get(&name) {
+                    log::debug!("Skipping failing upstream agent <{}>: {}", upstream, h);
                 }
+                continue;
+            }
+
+            let started = Instant::now();
+            let result = match upstream.connect(self.verify()).await {
+                Ok(mut client) => client.request_identities().await,
+                Err(e) => Err(e),
             };
-            let agent_identities = client.request_identities().await?;
-            {
-                for id in &agent_identities {
-                    known_keys.insert(id.pubkey.clone(), sock_path.clone());
+            let mut agent_identities = match result {
+                Ok(ids) => {
+                    self.record_success(&mut health, upstream, Some(started.elapsed()));
+                    ids
+                }
+                Err(e) => {
+                    self.record_failure(&mut health, upstream, &e);
+                    continue;
                 }
+            };
+            agent_identities.retain(|id| self.accept_identity(id, upstream));
+            let agent_identities =
+                self.attach_certificates(agent_identities, &attached_certs, upstream);
+            let now = Instant::now();
+            key_appearances.listed(
+                &name,
+                agent_identities.iter().map(|id| id.credential.key_data()),
+                now,
+            );
+            for id in &agent_identities {
+                let key = id.credential.key_data();
+                known_keys.insert(
+                    key.clone(),
+                    KnownKey {
+                        upstream: upstream.clone(),
+                        comment: id.comment.clone(),
+                        since: key_appearances.since(&name, key).unwrap_or(now),
+                    },
+                );
             }
             log::trace!(
                 "Got {} identities from {}",
                 agent_identities.len(),
-                sock_path.display()
+                upstream
             );
             identities.extend(agent_identities);
         }
 
+        // Every key is new the first time keys are listed, so nothing is announced then
+        let notify = self.listed_once.swap(true, Ordering::Relaxed);
+        let mut seen_keys = self.seen_keys.lock().expect("seen keys lock poisoned");
+        for (key, known) in known_keys.iter() {
+            if seen_keys.insert(key.clone()) && notify {
+                self.notify(Event::KeyAdded {
+                    fingerprint: key.fingerprint(Default::default()).to_string(),
+                    comment: known.comment.clone(),
+                    upstream: known.upstream.to_string(),
+                });
+            }
+        }
+
         Ok(identities)
     }
+
+    /// Offer certificates loaded from disk along with (or instead of) the upstream keys they were
+    /// issued for
+    fn attach_certificates(
+        &self,
+        identities: Vec<Identity>,
+        attached_certs: &MutexGuard<'_, AttachedCertificates>,
+        upstream: &Upstream,
+    ) -> Vec<Identity> {
+        let offered_certs: HashSet<_> = identities
+            .iter()
+            .filter_map(|id| match &id.credential {
+                PublicCredential::Cert(cert) => cert.to_bytes().ok(),
+                PublicCredential::Key(_) => None,
+            })
+            .collect();
+
+        let mut result = Vec::with_capacity(identities.len());
+        for id in identities {
+            let certs: Vec<_> = match &id.credential {
+                PublicCredential::Key(pubkey) => attached_certs
+                    .for_key(pubkey)
+                    .filter(|cert| {
+                        cert.to_bytes()
+                            .is_ok_and(|bytes| !offered_certs.contains(&bytes))
+                    })
+                    .map(|cert| Identity {
+                        credential: PublicCredential::Cert(Box::new(cert.clone())),
+                        comment: if cert.comment().is_empty() {
+                            id.comment.clone()
+                        } else {
+                            cert.comment().to_string()
+                        },
+                    })
+                    .filter(|cert_id| self.accept_identity(cert_id, upstream))
+                    .collect(),
+                PublicCredential::Cert(_) => vec![],
+            };
+            if certs.is_empty() || self.options.certificates.attach == AttachMode::Alongside {
+                result.push(id);
+            }
+            result.extend(certs);
+        }
+        result
+    }
+
+    /// Decide whether an identity from `upstream` should be offered to clients; certificates are
+    /// checked against their validity window and configured principals
+    fn accept_identity(&self, identity: &Identity, upstream: &Upstream) -> bool {
+        let key = identity.credential.key_data();
+        if !self.options.algorithms.allows_key(key) {
+            log::debug!(
+                "Dropping {} key {} from upstream agent <{}>: key type not allowed",
+                key.algorithm(),
+                key.fingerprint(Default::default()),
+                upstream
+            );
+            return false;
+        }
+        let PublicCredential::Cert(cert) = &identity.credential else {
+            return true;
+        };
+        match self.options.certificates.check(cert, SystemTime::now()) {
+            Ok(()) => true,
+            Err(reason) => {
+                log::info!(
+                    "Dropping certificate {:?} from upstream agent <{}>: {}",
+                    cert.key_id(),
+                    upstream,
+                    reason
+                );
+                false
+            }
+        }
+    }
 }
 
 impl Agent<SelfDeletingUnixListener> for MuxAgent {
     #[doc = "Create new session object when a new socket is accepted."]
     fn new_session(
         &mut self,
-        _socket: &<SelfDeletingUnixListener as ListeningSocket>::Stream,
+        socket: &<SelfDeletingUnixListener as ListeningSocket>::Stream,
     ) -> impl Session {
-        self.clone()
+        let mut session = self.clone();
+        if let Ok(peer) = verify::peer_credentials(socket) {
+            session.client = ClientProcess::of(&peer);
+        }
+        session.client_policy =
+            ClientPolicy::select(&self.options.client_policies, &session.client).cloned();
+        if let Some(policy) = &session.client_policy {
+            log::debug!(
+                "Client {} connected, with policy for programs {:?}",
+                session.client,
+                policy.programs
+            );
+        } else {
+            log::trace!("Client {} connected", session.client);
+        }
+        session
     }
 }
 
@@ -232,15 +1047,32 @@ impl Agent<SelfDeletingUnixListener> for MuxAgent {
 struct SelfDeletingUnixListener {
     path: PathBuf,
     listener: UnixListener,
+    allowed_clients: AllowedClients,
 }
 
 impl SelfDeletingUnixListener {
-    fn bind(path: impl AsRef<Path>) -> std::io::Result<Self> {
+    /// Listen at `path`, replacing any socket already there, but not any other kind of file
+    fn bind(path: impl AsRef<Path>, allowed_clients: AllowedClients) -> std::io::Result<Self> {
         let path = path.as_ref().to_path_buf();
-        if std::fs::remove_file(&path).is_ok() {
-            log::debug!("Deleted existing socket {}", path.display());
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if std::fs::remove_file(&path).is_ok() {
+                    log::debug!("Deleted existing socket {}", path.display());
+                }
+            }
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} exists, and isn't a socket", path.display()),
+                ))
+            }
+            Err(_) => (),
         }
-        UnixListener::bind(&path).map(|listener| Self { path, listener })
+        UnixListener::bind(&path).map(|listener| Self {
+            path,
+            listener,
+            allowed_clients,
+        })
     }
 }
 
@@ -255,9 +1087,20 @@ impl Drop for SelfDeletingUnixListener {
 impl ListeningSocket for SelfDeletingUnixListener {
     type Stream = tokio::net::UnixStream;
 
+    /// Accept the next connection from an allowed client, dropping any others
     async fn accept(&mut self) -> std::io::Result<Self::Stream> {
-        UnixListener::accept(&self.listener)
-            .await
-            .map(|(s, _addr)| s)
+        loop {
+            let (stream, _addr) = UnixListener::accept(&self.listener).await?;
+            match verify::peer_credentials(&stream) {
+                Ok(peer) if self.allowed_clients.allows(&peer) => return Ok(stream),
+                Ok(peer) => log::warn!(
+                    "Rejected client pid {} running as uid {}, gid {}",
+                    peer.pid.map_or_else(|| "unknown".into(), |p| p.to_string()),
+                    peer.uid,
+                    peer.gid
+                ),
+                Err(e) => log::warn!("Rejected client with unknown credentials: {}", e),
+            }
+        }
     }
 }
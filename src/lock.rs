@@ -0,0 +1,173 @@
+//! Locking the mux, with `ssh-add -x` or automatically after a while, so that keys are hidden and
+//! can't sign until it's unlocked with `ssh-add -X`
+
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// When the mux locks itself
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockConfig {
+    /// Lock after this many seconds without a signing request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<u64>,
+
+    /// Lock this many seconds after starting, or after last being unlocked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_unlocked: Option<u64>,
+
+    /// Keep offering keys to clients while locked, although they can't sign
+    pub list_while_locked: bool,
+}
+
+impl LockConfig {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct LockState {
+    locked: bool,
+    /// Passphrase given to `ssh-add -x`, or `None` if the mux locked itself
+    passphrase: Option<String>,
+    last_activity: Instant,
+    unlocked_at: Instant,
+}
+
+impl Default for LockState {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            locked: false,
+            passphrase: None,
+            last_activity: now,
+            unlocked_at: now,
+        }
+    }
+}
+
+impl LockState {
+    /// Whether the mux is locked at `now`, locking it first if it's been idle or unlocked for
+    /// too long
+    pub fn is_locked(&mut self, config: &LockConfig, now: Instant) -> bool {
+        if self.locked {
+            return true;
+        }
+        let expired = |since: Instant, secs: Option<u64>| {
+            secs.is_some_and(|secs| {
+                now.saturating_duration_since(since) >= Duration::from_secs(secs)
+            })
+        };
+        if expired(self.last_activity, config.idle_timeout) {
+            log::info!(
+                "Locking after {} seconds without signing requests",
+                config.idle_timeout.unwrap_or_default()
+            );
+        } else if expired(self.unlocked_at, config.max_unlocked) {
+            log::info!(
+                "Locking after {} seconds unlocked",
+                config.max_unlocked.unwrap_or_default()
+            );
+        } else {
+            return false;
+        }
+        self.locked = true;
+        self.passphrase = None;
+        true
+    }
+
+    /// Note a signing request, which postpones the idle timeout
+    pub fn record_activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Lock with `passphrase`, returning `false` if already locked
+    pub fn lock(&mut self, passphrase: String) -> bool {
+        if self.locked {
+            return false;
+        }
+        self.locked = true;
+        self.passphrase = Some(passphrase);
+        true
+    }
+
+    /// Whether the mux locked itself, so there's no passphrase to unlock it with
+    pub fn is_auto_locked(&self) -> bool {
+        self.locked && self.passphrase.is_none()
+    }
+
+    /// Unlock with `passphrase`, returning whether it was locked with that passphrase
+    ///
+    /// When the mux locked itself, no passphrase unlocks it; see [`Self::unlock_confirmed`].
+    pub fn unlock(&mut self, passphrase: &str, now: Instant) -> bool {
+        if !self.locked || self.passphrase.as_deref() != Some(passphrase) {
+            return false;
+        }
+        self.unlocked(now);
+        true
+    }
+
+    /// Unlock once the user has confirmed it, returning whether the mux had locked itself
+    pub fn unlock_confirmed(&mut self, now: Instant) -> bool {
+        if !self.is_auto_locked() {
+            return false;
+        }
+        self.unlocked(now);
+        true
+    }
+
+    fn unlocked(&mut self, now: Instant) {
+        self.locked = false;
+        self.passphrase = None;
+        self.last_activity = now;
+        self.unlocked_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_when_idle_or_unlocked_too_long() {
+        let config = LockConfig {
+            idle_timeout: Some(60),
+            max_unlocked: Some(600),
+            ..Default::default()
+        };
+        let mut state = LockState::default();
+        let start = state.last_activity;
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!state.is_locked(&config, at(59)));
+        state.record_activity(at(59));
+        assert!(!state.is_locked(&config, at(118)));
+        assert!(state.is_locked(&config, at(119)));
+
+        // Only confirmation unlocks after locking automatically
+        assert!(state.is_auto_locked());
+        assert!(!state.unlock("", at(120)));
+        assert!(state.unlock_confirmed(at(120)));
+        for secs in (170..720).step_by(50) {
+            state.record_activity(at(secs));
+            assert!(!state.is_locked(&config, at(secs)));
+        }
+        assert!(state.is_locked(&config, at(720)));
+    }
+
+    #[test]
+    fn unlock_needs_lock_passphrase() {
+        let mut state = LockState::default();
+        let now = Instant::now();
+        assert!(!state.unlock("secret", now));
+        assert!(state.lock("secret".into()));
+        assert!(!state.lock("other".into()));
+        assert!(!state.unlock_confirmed(now));
+        assert!(state.is_locked(&LockConfig::default(), now));
+        assert!(!state.unlock("wrong", now));
+        assert!(state.unlock("secret", now));
+        assert!(!state.is_locked(&LockConfig::default(), now));
+    }
+}
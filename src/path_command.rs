@@ -0,0 +1,101 @@
+//! Upstream agents whose socket path is printed by a command, such as
+//! `gpgconf --list-dirs agent-ssh-socket`
+
+use std::{fmt, process::Stdio, sync::Arc, time::Duration};
+
+use ssh_agent_lib::{agent::Session, error::AgentError};
+use tokio::{process::Command, sync::Mutex};
+
+use crate::{address::UpstreamAddress, verify::Verify};
+
+/// How long the command may take to print the socket path
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An upstream agent at the path printed by a command
+///
+/// The command is run when the agent is first needed, and again whenever connecting to the last
+/// path it printed fails.
+#[derive(Clone)]
+pub(crate) struct PathCommand {
+    command: Vec<String>,
+    resolved: Arc<Mutex<Option<UpstreamAddress>>>,
+}
+
+impl PathCommand {
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            resolved: Default::default(),
+        }
+    }
+
+    pub async fn connect(&self, verify: Verify) -> Result<Box<dyn Session>, AgentError> {
+        let mut resolved = self.resolved.lock().await;
+        if let Some(address) = resolved.as_ref() {
+            match address.connect(verify).await {
+                Ok(client) => return Ok(client),
+                Err(e) => log::debug!(
+                    "Resolving socket path again after failing to connect to {}: {}",
+                    address,
+                    e
+                ),
+            }
+        }
+
+        let address = self.resolve().await?;
+        if resolved.as_ref() != Some(&address) {
+            log::info!("Resolved upstream agent socket {} from {}", address, self);
+        }
+        let client = address.connect(verify).await;
+        *resolved = Some(address);
+        client
+    }
+
+    /// Run the command, and parse the first line of its output
+    async fn resolve(&self) -> Result<UpstreamAddress, AgentError> {
+        let failed = |reason: String| {
+            AgentError::Other(
+                format!("Failed to resolve socket path from {}: {}", self, reason).into(),
+            )
+        };
+
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| failed("empty command".into()))?;
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(COMMAND_TIMEOUT, output)
+            .await
+            .map_err(|_| failed("timed out".into()))?
+            .map_err(|e| failed(e.to_string()))?;
+        if !output.status.success() {
+            return Err(failed(output.status.to_string()));
+        }
+
+        let stdout = String::from_utf8(output.stdout).map_err(|e| failed(e.to_string()))?;
+        let path = stdout.lines().next().unwrap_or_default().trim();
+        if path.is_empty() {
+            return Err(failed("no output".into()));
+        }
+        path.parse().map_err(|e| failed(format!("{e}")))
+    }
+}
+
+impl fmt::Debug for PathCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PathCommand")
+            .field("command", &self.command)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for PathCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$({})", self.command.join(" "))
+    }
+}
@@ -0,0 +1,201 @@
+//! What a signing request is for, found by parsing the data to be signed, and policies restricting
+//! keys to particular purposes
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::{ssh_encoding::Decode, ssh_key::public::KeyData as PubKeyData};
+
+use crate::clients;
+
+/// `SSH_MSG_USERAUTH_REQUEST`, from RFC 4252
+const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+/// Magic preamble of data signed by `ssh-keygen -Y sign`, from OpenSSH's `PROTOCOL.sshsig`
+const SSHSIG_MAGIC: &[u8] = b"SSHSIG";
+/// Authentication method that also includes the server's host key, from OpenSSH's `PROTOCOL`
+const PUBLICKEY_HOSTBOUND: &str = "publickey-hostbound-v00@openssh.com";
+
+/// Purpose of a signing request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Purpose {
+    /// Authenticating to an SSH server as `user`, for `service`
+    UserAuth {
+        user: String,
+        service: String,
+        /// Host key of the server, if the client included it
+        host_key: Option<PubKeyData>,
+    },
+    /// An `SSHSIG` signature, such as a git commit signature, in `namespace`
+    Sshsig { namespace: String },
+    /// Data that isn't either of the above
+    Unknown,
+}
+
+impl Purpose {
+    pub fn parse(data: &[u8]) -> Self {
+        if let Some(mut rest) = data.strip_prefix(SSHSIG_MAGIC) {
+            return String::decode(&mut rest)
+                .map(|namespace| Self::Sshsig { namespace })
+                .unwrap_or(Self::Unknown);
+        }
+        Self::parse_userauth(data).unwrap_or(Self::Unknown)
+    }
+
+    fn parse_userauth(mut data: &[u8]) -> Option<Self> {
+        let reader = &mut data;
+        let _session_id = Vec::<u8>::decode(reader).ok()?;
+        if u8::decode(reader).ok()? != SSH_MSG_USERAUTH_REQUEST {
+            return None;
+        }
+        let user = String::decode(reader).ok()?;
+        let service = String::decode(reader).ok()?;
+        let method = String::decode(reader).ok()?;
+        if !method.starts_with("publickey") {
+            return None;
+        }
+        let host_key = (method == PUBLICKEY_HOSTBOUND)
+            .then(|| {
+                let _has_signature = u8::decode(reader).ok()?;
+                let _algorithm = String::decode(reader).ok()?;
+                let _key = Vec::<u8>::decode(reader).ok()?;
+                let host_key = Vec::<u8>::decode(reader).ok()?;
+                PubKeyData::decode(&mut host_key.as_slice()).ok()
+            })
+            .flatten();
+        Some(Self::UserAuth {
+            user,
+            service,
+            host_key,
+        })
+    }
+}
+
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserAuth { user, .. } => write!(f, "user authentication as {:?}", user),
+            Self::Sshsig { namespace } => {
+                write!(f, "SSHSIG signature in namespace {:?}", namespace)
+            }
+            Self::Unknown => f.write_str("unrecognized data"),
+        }
+    }
+}
+
+/// Purposes that some keys may be used for
+///
+/// Requests to sign data that isn't recognized as either purpose are refused for keys that a
+/// purpose policy applies to.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PurposePolicy {
+    /// Fingerprints (`SHA256:...`) or public keys of the keys this policy applies to
+    ///
+    /// A policy without any applies to every key that no other policy applies to.
+    pub keys: Vec<String>,
+
+    /// User names that the keys may authenticate to SSH servers as
+    ///
+    /// If not set, the keys may authenticate as any user; if empty, they can't be used for
+    /// authentication at all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub users: Option<Vec<String>>,
+
+    /// `SSHSIG` namespaces, such as `git` or `file`, that the keys may sign in
+    ///
+    /// If not set, the keys may sign in any namespace; if empty, they can't make `SSHSIG`
+    /// signatures at all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub namespaces: Option<Vec<String>>,
+}
+
+impl PurposePolicy {
+    /// Choose the policy for `key`: the first that lists it, or else the first that doesn't list
+    /// any keys
+    pub(crate) fn select<'a>(policies: &'a [Self], key: &PubKeyData) -> Option<&'a Self> {
+        policies
+            .iter()
+            .find(|p| !p.keys.is_empty() && clients::key_listed(&p.keys, key))
+            .or_else(|| policies.iter().find(|p| p.keys.is_empty()))
+    }
+
+    pub(crate) fn allows(&self, purpose: &Purpose) -> bool {
+        let listed = |allowed: &Option<Vec<String>>, value: &String| {
+            allowed.as_ref().is_none_or(|a| a.contains(value))
+        };
+        match purpose {
+            Purpose::UserAuth { user, .. } => listed(&self.users, user),
+            Purpose::Sshsig { namespace } => listed(&self.namespaces, namespace),
+            Purpose::Unknown => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_agent_lib::{ssh_encoding::Encode, ssh_key::public::Ed25519PublicKey};
+
+    use super::*;
+
+    #[test]
+    fn parse_purposes() -> Result<(), ssh_agent_lib::ssh_encoding::Error> {
+        let mut userauth = vec![];
+        [0xab_u8; 32].as_slice().encode(&mut userauth)?;
+        SSH_MSG_USERAUTH_REQUEST.encode(&mut userauth)?;
+        "alice".encode(&mut userauth)?;
+        "ssh-connection".encode(&mut userauth)?;
+        PUBLICKEY_HOSTBOUND.encode(&mut userauth)?;
+        1_u8.encode(&mut userauth)?;
+        "ssh-ed25519".encode(&mut userauth)?;
+        [0_u8; 51].as_slice().encode(&mut userauth)?;
+        let host_key: PubKeyData = Ed25519PublicKey([7; 32]).into();
+        host_key.encode_prefixed(&mut userauth)?;
+        assert_eq!(
+            Purpose::parse(&userauth),
+            Purpose::UserAuth {
+                user: "alice".into(),
+                service: "ssh-connection".into(),
+                host_key: Some(host_key),
+            }
+        );
+
+        let mut sshsig = SSHSIG_MAGIC.to_vec();
+        "git".encode(&mut sshsig)?;
+        "".encode(&mut sshsig)?;
+        "sha512".encode(&mut sshsig)?;
+        [0_u8; 64].as_slice().encode(&mut sshsig)?;
+        assert_eq!(
+            Purpose::parse(&sshsig),
+            Purpose::Sshsig {
+                namespace: "git".into()
+            }
+        );
+
+        assert_eq!(Purpose::parse(b"arbitrary data"), Purpose::Unknown);
+        assert_eq!(Purpose::parse(b"SSHSIG"), Purpose::Unknown);
+        Ok(())
+    }
+
+    #[test]
+    fn policy_allows_purposes() {
+        let git_only = PurposePolicy {
+            users: Some(vec![]),
+            namespaces: Some(vec!["git".into()]),
+            ..Default::default()
+        };
+        let sshsig = |namespace: &str| Purpose::Sshsig {
+            namespace: namespace.into(),
+        };
+        assert!(git_only.allows(&sshsig("git")));
+        assert!(!git_only.allows(&sshsig("file")));
+        assert!(!git_only.allows(&Purpose::UserAuth {
+            user: "alice".into(),
+            service: "ssh-connection".into(),
+            host_key: None,
+        }));
+
+        let unrestricted = PurposePolicy::default();
+        assert!(unrestricted.allows(&sshsig("file")));
+        assert!(!unrestricted.allows(&Purpose::Unknown));
+    }
+}
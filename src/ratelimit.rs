@@ -0,0 +1,348 @@
+//! Limiting how fast clients can ask for signatures, per client and per key, so that a runaway or
+//! compromised process can't use keys without bound
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::ssh_key::public::KeyData as PubKeyData;
+
+use crate::{clients::ClientProcess, hooks};
+
+/// How long the `on_limit` command may run before it's killed
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A token bucket: up to `burst` requests at once, refilled at `per_minute` requests a minute
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// What identifies a client, for its rate limit
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientBy {
+    /// The user the client runs as, so that all of a user's programs share one limit
+    #[default]
+    Uid,
+    /// The client's executable
+    Exe,
+}
+
+/// Limits on signing requests
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Limit for each client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_client: Option<RateLimit>,
+
+    pub client_by: ClientBy,
+
+    /// Limit for each key, whichever clients use it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_key: Option<RateLimit>,
+
+    /// Command to run when a client or key first goes over its limit, with details in
+    /// `SSH_AGENT_MUX_*` environment variables
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub on_limit: Vec<String>,
+}
+
+impl RateLimits {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    fn client_id(&self, client: &ClientProcess) -> String {
+        match self.client_by {
+            ClientBy::Uid => client
+                .credentials()
+                .map_or_else(|| "unknown user".into(), |c| format!("uid {}", c.uid)),
+            ClientBy::Exe => client
+                .exe()
+                .map_or_else(|| "unknown program".into(), |exe| exe.display().to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum BucketId {
+    Client(String),
+    Key(PubKeyData),
+}
+
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// Whether the last request was over the limit, so that only the first of a run of
+    /// violations is reported
+    limited: bool,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst.into(),
+            updated: now,
+            limited: false,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        self.tokens = self.tokens_at(limit, now);
+        self.updated = now;
+    }
+
+    fn tokens_at(&self, limit: &RateLimit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * f64::from(limit.per_minute) / 60.0).min(limit.burst.into())
+    }
+}
+
+/// Which limit a request went over
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Violation {
+    /// `client` or `key`
+    pub limit: &'static str,
+    /// The client, or the key's fingerprint
+    pub subject: String,
+    /// Whether this is the first request over the limit since it was last within it
+    pub first: bool,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limit for {} {} exceeded", self.limit, self.subject)
+    }
+}
+
+/// Token buckets of every client and key that has asked for a signature
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    buckets: HashMap<BucketId, Bucket>,
+}
+
+impl RateLimiter {
+    /// Take a token from the buckets of `client` and `key`, or neither if either is empty
+    pub fn check(
+        &mut self,
+        limits: &RateLimits,
+        client: &ClientProcess,
+        key: &PubKeyData,
+        now: Instant,
+    ) -> Result<(), Violation> {
+        let buckets = [
+            limits
+                .per_client
+                .map(|limit| (BucketId::Client(limits.client_id(client)), limit, "client")),
+            limits
+                .per_key
+                .map(|limit| (BucketId::Key(key.clone()), limit, "key")),
+        ];
+        self.prune(limits, now);
+
+        let mut violation = None;
+        for (id, limit, name) in buckets.iter().flatten() {
+            let bucket = self
+                .buckets
+                .entry(id.clone())
+                .or_insert_with(|| Bucket::new(limit, now));
+            bucket.refill(limit, now);
+            if bucket.tokens < 1.0 && violation.is_none() {
+                let subject = match id {
+                    BucketId::Client(client) => client.clone(),
+                    BucketId::Key(key) => key.fingerprint(Default::default()).to_string(),
+                };
+                violation = Some(Violation {
+                    limit: name,
+                    subject,
+                    first: !bucket.limited,
+                });
+                bucket.limited = true;
+            }
+        }
+        if let Some(violation) = violation {
+            return Err(violation);
+        }
+        for (id, _, _) in buckets.iter().flatten() {
+            if let Some(bucket) = self.buckets.get_mut(id) {
+                bucket.tokens -= 1.0;
+                bucket.limited = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget buckets that have refilled, which are the same as new ones, and buckets of limits
+    /// that are no longer configured
+    ///
+    /// Buckets still over their limit are kept, even if they're full because their `burst` is 0,
+    /// so that a run of violations is only reported once.
+    fn prune(&mut self, limits: &RateLimits, now: Instant) {
+        self.buckets.retain(|id, bucket| {
+            let limit = match id {
+                BucketId::Client(_) => limits.per_client,
+                BucketId::Key(_) => limits.per_key,
+            };
+            limit.is_some_and(|limit| {
+                let tokens = bucket.tokens_at(&limit, now);
+                tokens < limit.burst.into() || (bucket.limited && tokens < 1.0)
+            })
+        });
+    }
+}
+
+/// Run the `on_limit` command in the background, without holding up the request
+pub(crate) fn run_hook(command: &[String], violation: &Violation, client: &ClientProcess) {
+    let env = [
+        ("SSH_AGENT_MUX_LIMIT", violation.limit.to_string()),
+        ("SSH_AGENT_MUX_SUBJECT", violation.subject.clone()),
+        ("SSH_AGENT_MUX_CLIENT", client.to_string()),
+    ]
+    .map(|(name, value)| (name.to_string(), value));
+    hooks::spawn(command, &env, None, HOOK_TIMEOUT);
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_agent_lib::ssh_key::public::Ed25519PublicKey;
+
+    use super::*;
+
+    fn limits(per_client: Option<RateLimit>, per_key: Option<RateLimit>) -> RateLimits {
+        RateLimits {
+            per_client,
+            per_key,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bursts_then_refills() {
+        let limits = limits(
+            Some(RateLimit {
+                burst: 2,
+                per_minute: 6,
+            }),
+            None,
+        );
+        let client = ClientProcess::default();
+        let key: PubKeyData = Ed25519PublicKey([1; 32]).into();
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert!(limiter.check(&limits, &client, &key, now).is_ok());
+        assert!(limiter.check(&limits, &client, &key, now).is_ok());
+        let violation = limiter.check(&limits, &client, &key, now).unwrap_err();
+        assert_eq!(violation.limit, "client");
+        assert!(violation.first);
+        assert!(
+            !limiter
+                .check(&limits, &client, &key, now)
+                .unwrap_err()
+                .first
+        );
+
+        // One token every ten seconds
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.check(&limits, &client, &key, later).is_ok());
+        assert!(
+            limiter
+                .check(&limits, &client, &key, later)
+                .unwrap_err()
+                .first
+        );
+
+        // Once refilled, buckets are forgotten
+        let much_later = later + Duration::from_secs(60);
+        limiter.prune(&limits, much_later);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn keys_limited_separately() {
+        let limits = limits(
+            None,
+            Some(RateLimit {
+                burst: 1,
+                per_minute: 0,
+            }),
+        );
+        let client = ClientProcess::default();
+        let key: PubKeyData = Ed25519PublicKey([1; 32]).into();
+        let other_key: PubKeyData = Ed25519PublicKey([2; 32]).into();
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert!(limiter.check(&limits, &client, &key, now).is_ok());
+        assert_eq!(
+            limiter
+                .check(&limits, &client, &key, now)
+                .unwrap_err()
+                .limit,
+            "key"
+        );
+        assert!(limiter.check(&limits, &client, &other_key, now).is_ok());
+    }
+
+    #[test]
+    fn refused_requests_take_no_tokens() {
+        let limits = limits(
+            Some(RateLimit {
+                burst: 2,
+                per_minute: 0,
+            }),
+            Some(RateLimit {
+                burst: 1,
+                per_minute: 0,
+            }),
+        );
+        let client = ClientProcess::default();
+        let key: PubKeyData = Ed25519PublicKey([1; 32]).into();
+        let other_key: PubKeyData = Ed25519PublicKey([2; 32]).into();
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert!(limiter.check(&limits, &client, &key, now).is_ok());
+        assert!(limiter.check(&limits, &client, &key, now).is_err());
+        // The client still has a token left, because the key's limit refused the last request
+        assert!(limiter.check(&limits, &client, &other_key, now).is_ok());
+        assert!(limiter.check(&limits, &client, &other_key, now).is_err());
+    }
+
+    #[test]
+    fn zero_burst_violations_reported_once() {
+        let limits = limits(
+            Some(RateLimit {
+                burst: 0,
+                per_minute: 60,
+            }),
+            None,
+        );
+        let client = ClientProcess::default();
+        let key: PubKeyData = Ed25519PublicKey([1; 32]).into();
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert!(
+            limiter
+                .check(&limits, &client, &key, now)
+                .unwrap_err()
+                .first
+        );
+        let later = now + Duration::from_secs(10);
+        assert!(
+            !limiter
+                .check(&limits, &client, &key, later)
+                .unwrap_err()
+                .first
+        );
+    }
+}
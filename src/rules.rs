@@ -0,0 +1,475 @@
+//! An ordered list of rules deciding, for each key and request, whether the key is offered to
+//! clients and whether it may sign
+
+use std::{
+    env, fmt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, NaiveTime};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{clients, purpose::Purpose};
+
+/// How long to wait for the user to answer a confirmation prompt
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What to do with a request that a rule matches
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Offer the key, and sign with it
+    #[default]
+    Allow,
+    /// Offer the key, but refuse to sign with it
+    Deny,
+    /// Offer the key, and ask the user before signing with it
+    Confirm,
+    /// Neither offer the key nor sign with it
+    Hide,
+    /// Log the request, and carry on to the next rule
+    Log,
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+            Self::Confirm => "confirm",
+            Self::Hide => "hide",
+            Self::Log => "log",
+        })
+    }
+}
+
+/// What a signature is for, as rules match it
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PurposeKind {
+    UserAuth,
+    Sshsig,
+    Unknown,
+}
+
+impl FromStr for PurposeKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "userauth" => Ok(Self::UserAuth),
+            "sshsig" => Ok(Self::Sshsig),
+            "unknown" => Ok(Self::Unknown),
+            _ => Err(format!("unknown purpose {s:?}")),
+        }
+    }
+}
+
+/// A range of local times of day, such as `08:00-20:00`, which may wrap past midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Hours {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Hours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for Hours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid hours {s:?}, expected a range such as \"08:00-20:00\"");
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid());
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl From<Hours> for String {
+    fn from(hours: Hours) -> Self {
+        format!(
+            "{}-{}",
+            hours.start.format("%H:%M"),
+            hours.end.format("%H:%M")
+        )
+    }
+}
+
+/// A rule matching some requests, with what to do with them
+///
+/// Every condition that's set must match. Lists match if any of their entries does.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// Name of the rule, for logging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Fingerprints (`SHA256:...`) or public keys of keys
+    pub keys: Vec<String>,
+
+    /// Socket paths or glob patterns of upstream agents holding the key
+    pub upstreams: Vec<PathBuf>,
+
+    /// Executable paths or glob patterns, or file names, of the client or a process that
+    /// started it
+    pub programs: Vec<String>,
+
+    /// User IDs the client runs as
+    pub uids: Vec<u32>,
+
+    /// Fingerprints or public keys of the host key of the server that the client is connected
+    /// to, from the `session-bind@openssh.com` extension
+    pub host_keys: Vec<String>,
+
+    /// Whether the client is using the mux through a forwarded agent connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarded: Option<bool>,
+
+    /// What the signature is for
+    ///
+    /// Rules with `purposes`, `users` or `namespaces` never match when keys are listed.
+    pub purposes: Vec<PurposeKind>,
+
+    /// User names being authenticated as
+    pub users: Vec<String>,
+
+    /// `SSHSIG` namespaces being signed in
+    pub namespaces: Vec<String>,
+
+    /// Local times of day
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hours: Option<Hours>,
+
+    pub action: RuleAction,
+}
+
+/// A request to list or sign with a key, as rules see it
+#[derive(Clone, Debug)]
+pub struct RuleRequest {
+    /// Fingerprint of the key
+    pub key: Option<String>,
+    pub upstream: Option<String>,
+    /// Executables of the client, and of the processes that started it, nearest first
+    pub programs: Vec<PathBuf>,
+    pub uid: Option<u32>,
+    /// Fingerprint of the host key of the server that the client is connected to
+    pub host_key: Option<String>,
+    pub forwarded: bool,
+    /// What the signature is for, or `None` when listing keys
+    pub purpose: Option<PurposeKind>,
+    pub user: Option<String>,
+    pub namespace: Option<String>,
+    /// Local time
+    pub time: NaiveDateTime,
+}
+
+impl Default for RuleRequest {
+    fn default() -> Self {
+        Self {
+            key: None,
+            upstream: None,
+            programs: vec![],
+            uid: None,
+            host_key: None,
+            forwarded: false,
+            purpose: None,
+            user: None,
+            namespace: None,
+            time: chrono::Local::now().naive_local(),
+        }
+    }
+}
+
+impl RuleRequest {
+    pub(crate) fn set_purpose(&mut self, purpose: &Purpose) {
+        match purpose {
+            Purpose::UserAuth { user, .. } => {
+                self.purpose = Some(PurposeKind::UserAuth);
+                self.user = Some(user.clone());
+            }
+            Purpose::Sshsig { namespace } => {
+                self.purpose = Some(PurposeKind::Sshsig);
+                self.namespace = Some(namespace.clone());
+            }
+            Purpose::Unknown => self.purpose = Some(PurposeKind::Unknown),
+        }
+    }
+}
+
+/// Whether `fingerprint` is one of `keys`, given as fingerprints or public keys
+fn key_listed(keys: &[String], fingerprint: Option<&str>) -> bool {
+    fingerprint.is_some_and(|fingerprint| clients::fingerprint_listed(keys, fingerprint))
+}
+
+fn value_listed(values: &[String], value: Option<&String>) -> bool {
+    value.is_some_and(|v| values.contains(v))
+}
+
+impl Rule {
+    /// `Ok` if the rule matches `request`, or else the first condition that doesn't
+    fn matches(&self, request: &RuleRequest) -> Result<(), &'static str> {
+        let check = |matched: bool, condition| if matched { Ok(()) } else { Err(condition) };
+        if !self.keys.is_empty() {
+            check(key_listed(&self.keys, request.key.as_deref()), "keys")?;
+        }
+        if !self.upstreams.is_empty() {
+            let matched = request.upstream.as_deref().is_some_and(|upstream| {
+                self.upstreams.iter().any(|pattern| {
+                    glob::Pattern::new(&pattern.to_string_lossy())
+                        .is_ok_and(|p| p.matches(upstream))
+                })
+            });
+            check(matched, "upstreams")?;
+        }
+        if !self.programs.is_empty() {
+            let matched = request.programs.iter().any(|exe| {
+                self.programs
+                    .iter()
+                    .any(|program| clients::program_matches(exe, program))
+            });
+            check(matched, "programs")?;
+        }
+        if !self.uids.is_empty() {
+            check(
+                request.uid.is_some_and(|uid| self.uids.contains(&uid)),
+                "uids",
+            )?;
+        }
+        if !self.host_keys.is_empty() {
+            check(
+                key_listed(&self.host_keys, request.host_key.as_deref()),
+                "host_keys",
+            )?;
+        }
+        if let Some(forwarded) = self.forwarded {
+            check(forwarded == request.forwarded, "forwarded")?;
+        }
+        if !self.purposes.is_empty() {
+            check(
+                request
+                    .purpose
+                    .is_some_and(|purpose| self.purposes.contains(&purpose)),
+                "purposes",
+            )?;
+        }
+        if !self.users.is_empty() {
+            check(value_listed(&self.users, request.user.as_ref()), "users")?;
+        }
+        if !self.namespaces.is_empty() {
+            check(
+                value_listed(&self.namespaces, request.namespace.as_ref()),
+                "namespaces",
+            )?;
+        }
+        if let Some(hours) = self.hours {
+            check(hours.contains(request.time.time()), "hours")?;
+        }
+        Ok(())
+    }
+
+    fn describe(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("rule {} ({:?})", index + 1, name),
+            None => format!("rule {}", index + 1),
+        }
+    }
+}
+
+impl fmt::Display for RuleRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.purpose {
+            Some(_) => f.write_str("sign with")?,
+            None => f.write_str("list")?,
+        }
+        write!(f, " key {}", self.key.as_deref().unwrap_or("(any)"))?;
+        if let Some(upstream) = &self.upstream {
+            write!(f, " from <{}>", upstream)?;
+        }
+        if let Some(exe) = self.programs.first() {
+            write!(f, " for {}", exe.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of evaluating rules for a request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Decision {
+    pub action: RuleAction,
+    /// Index of the rule that decided, or `None` if no rule did, and the request is allowed
+    pub rule: Option<usize>,
+}
+
+/// Find the first rule, other than `log` rules, matching `request`
+pub(crate) fn evaluate(rules: &[Rule], request: &RuleRequest) -> Decision {
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.matches(request).is_err() {
+            continue;
+        }
+        if rule.action == RuleAction::Log {
+            log::info!("{} matched request to {}", rule.describe(i), request);
+            continue;
+        }
+        log::debug!(
+            "{} matched request to {}: {}",
+            rule.describe(i),
+            request,
+            rule.action
+        );
+        return Decision {
+            action: rule.action,
+            rule: Some(i),
+        };
+    }
+    Decision {
+        action: RuleAction::Allow,
+        rule: None,
+    }
+}
+
+/// Describe how each rule applies to `request`, for a dry run
+pub fn explain(rules: &[Rule], request: &RuleRequest) -> String {
+    let mut lines = vec![format!("Request: {}", request)];
+    for (i, rule) in rules.iter().enumerate() {
+        match rule.matches(request) {
+            Err(condition) => {
+                lines.push(format!("{}: doesn't match {}", rule.describe(i), condition))
+            }
+            Ok(()) if rule.action == RuleAction::Log => {
+                lines.push(format!("{}: matches, and logs", rule.describe(i)))
+            }
+            Ok(()) => {
+                lines.push(format!("{}: matches", rule.describe(i)));
+                lines.push(format!("Decision: {}", rule.action));
+                return lines.join("\n");
+            }
+        }
+    }
+    lines.push("Decision: allow, as no rule matches".into());
+    lines.join("\n")
+}
+
+/// Ask the user to confirm a signature with the `SSH_ASKPASS` program, as `ssh-agent` does for
+/// keys added with `ssh-add -c`
+pub(crate) async fn confirm(prompt: &str) -> bool {
+    let program = env::var_os("SSH_ASKPASS").unwrap_or_else(|| "ssh-askpass".into());
+    let status = Command::new(&program)
+        .arg(prompt)
+        .env("SSH_ASKPASS_PROMPT", "confirm")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .status();
+    match tokio::time::timeout(CONFIRM_TIMEOUT, status).await {
+        Ok(Ok(status)) => status.success(),
+        Ok(Err(e)) => {
+            log::error!(
+                "Failed to run askpass program {}: {}",
+                Path::new(&program).display(),
+                e
+            );
+            false
+        }
+        Err(_) => {
+            log::warn!("Timed out waiting for confirmation");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Timelike;
+
+    use super::*;
+
+    fn rule(action: RuleAction) -> Rule {
+        Rule {
+            action,
+            ..Default::default()
+        }
+    }
+
+    fn request() -> RuleRequest {
+        RuleRequest {
+            key: Some("SHA256:key".into()),
+            upstream: Some("/run/user/1000/yubikey-agent.sock".into()),
+            programs: vec!["/usr/bin/ssh".into(), "/usr/bin/git".into()],
+            uid: Some(1000),
+            time: NaiveDateTime::parse_from_str("2026-10-19 09:30", "%Y-%m-%d %H:%M").unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = [
+            Rule {
+                programs: vec!["git".into()],
+                purposes: vec![PurposeKind::UserAuth],
+                ..rule(RuleAction::Deny)
+            },
+            Rule {
+                upstreams: vec!["/run/user/*/yubikey-agent.sock".into()],
+                ..rule(RuleAction::Log)
+            },
+            Rule {
+                keys: vec!["SHA256:key".into()],
+                hours: Some("08:00-20:00".to_string().try_into().unwrap()),
+                ..rule(RuleAction::Confirm)
+            },
+            rule(RuleAction::Hide),
+        ];
+
+        // Rules matching on purpose are skipped when listing keys
+        assert_eq!(
+            evaluate(&rules, &request()),
+            Decision {
+                action: RuleAction::Confirm,
+                rule: Some(2),
+            }
+        );
+        let mut signing = request();
+        signing.purpose = Some(PurposeKind::UserAuth);
+        assert_eq!(evaluate(&rules, &signing).rule, Some(0));
+
+        let mut at_night = request();
+        at_night.time = at_night.time.with_hour(22).unwrap();
+        assert_eq!(evaluate(&rules, &at_night).action, RuleAction::Hide);
+
+        assert_eq!(evaluate(&rules[..2], &request()).rule, None);
+        let explanation = explain(&rules, &at_night);
+        assert!(explanation.contains("rule 1: doesn't match purposes"));
+        assert!(explanation.contains("rule 3: doesn't match hours"));
+        assert!(explanation.ends_with("Decision: hide"));
+    }
+
+    #[test]
+    fn hours_wrap_past_midnight() {
+        let night = Hours::try_from("22:00-06:00".to_string()).unwrap();
+        let time = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        assert!(night.contains(time(23, 0)));
+        assert!(night.contains(time(5, 59)));
+        assert!(!night.contains(time(6, 0)));
+        assert!(!night.contains(time(12, 0)));
+        assert!(Hours::try_from("8-20".to_string()).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Signing with private keys held by the mux itself, rather than by an upstream agent
+
+use rsa::{
+    pkcs1v15::SigningKey,
+    sha2::{Sha256, Sha512},
+    signature::{SignatureEncoding, Signer},
+    BigUint, RsaPrivateKey,
+};
+use ssh_agent_lib::{
+    error::AgentError,
+    proto::{
+        signature::{RSA_SHA2_256, RSA_SHA2_512},
+        EcdsaPrivateKey, PrivateKeyData,
+    },
+    ssh_key::{
+        private::{EcdsaKeypair, KeypairData, RsaKeypair},
+        public::{EcdsaPublicKey, KeyData as PubKeyData},
+        Algorithm, HashAlg, PrivateKey, Signature,
+    },
+};
+
+/// Sign `data` with `key`, honoring the RSA hash algorithm requested in `flags`
+///
+/// Legacy `ssh-rsa` (SHA-1) signatures are refused.
+pub(crate) fn sign(key: &PrivateKey, data: &[u8], flags: u32) -> Result<Signature, AgentError> {
+    match key.key_data() {
+        KeypairData::Rsa(keypair) => sign_rsa(keypair, data, flags),
+        KeypairData::Ed25519(_) | KeypairData::Ecdsa(_) => key.try_sign(data).map_err(|e| {
+            log::error!("Failed to sign with {} key: {}", key.algorithm(), e);
+            AgentError::Failure
+        }),
+        _ => {
+            log::error!("Signing with {} keys is not supported", key.algorithm());
+            Err(AgentError::Failure)
+        }
+    }
+}
+
+fn sign_rsa(keypair: &RsaKeypair, data: &[u8], flags: u32) -> Result<Signature, AgentError> {
+    let hash = if flags & RSA_SHA2_512 != 0 {
+        HashAlg::Sha512
+    } else if flags & RSA_SHA2_256 != 0 {
+        HashAlg::Sha256
+    } else {
+        log::error!("Refusing to make a SHA-1 (ssh-rsa) signature");
+        return Err(AgentError::Failure);
+    };
+
+    // Converted by hand, because ssh-key's TryFrom<&RsaKeypair> passes the wrong primes
+    let private_key = RsaPrivateKey::from_components(
+        BigUint::try_from(&keypair.public.n).map_err(AgentError::other)?,
+        BigUint::try_from(&keypair.public.e).map_err(AgentError::other)?,
+        BigUint::try_from(&keypair.private.d).map_err(AgentError::other)?,
+        vec![
+            BigUint::try_from(&keypair.private.p).map_err(AgentError::other)?,
+            BigUint::try_from(&keypair.private.q).map_err(AgentError::other)?,
+        ],
+    )
+    .map_err(AgentError::other)?;
+    let signature = match hash {
+        HashAlg::Sha256 => SigningKey::<Sha256>::new(private_key).sign(data).to_vec(),
+        HashAlg::Sha512 => SigningKey::<Sha512>::new(private_key).sign(data).to_vec(),
+        _ => unreachable!(),
+    };
+
+    Signature::new(Algorithm::Rsa { hash: Some(hash) }, signature).map_err(AgentError::other)
+}
+
+/// Combine the public key from a certificate with the private key sent alongside it by `ssh-add`
+pub(crate) fn keypair_from_parts(
+    public: &PubKeyData,
+    private: PrivateKeyData,
+) -> Option<KeypairData> {
+    let keypair = match (public, private) {
+        (PubKeyData::Ed25519(_), PrivateKeyData::Ed25519(keypair)) => keypair.into(),
+        (PubKeyData::Rsa(public), PrivateKeyData::Rsa(private)) => RsaKeypair {
+            public: public.clone(),
+            private,
+        }
+        .into(),
+        (PubKeyData::Ecdsa(public), PrivateKeyData::Ecdsa(private)) => match (public, private) {
+            (EcdsaPublicKey::NistP256(public), EcdsaPrivateKey::NistP256(private)) => {
+                EcdsaKeypair::NistP256 {
+                    public: *public,
+                    private,
+                }
+            }
+            (EcdsaPublicKey::NistP384(public), EcdsaPrivateKey::NistP384(private)) => {
+                EcdsaKeypair::NistP384 {
+                    public: *public,
+                    private,
+                }
+            }
+            (EcdsaPublicKey::NistP521(public), EcdsaPrivateKey::NistP521(private)) => {
+                EcdsaKeypair::NistP521 {
+                    public: *public,
+                    private,
+                }
+            }
+            _ => return None,
+        }
+        .into(),
+        _ => return None,
+    };
+    Some(keypair)
+}
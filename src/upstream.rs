@@ -0,0 +1,164 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use ssh_agent_lib::{agent::Session, error::AgentError};
+
+use crate::{
+    address::{AddressParseError, UpstreamAddress},
+    control_path,
+    discovery::{self, GroupConfig},
+    exec::ExecUpstream,
+    keyfiles::{KeyFiles, KeyFilesConfig},
+    keystore::{Keystore, KeystoreConfig},
+    path_command::PathCommand,
+    verify::Verify,
+};
+
+/// An upstream agent entry from the configuration
+///
+/// Upstream agents are usually specified as a path to their socket, or an address such as
+/// `tcp:host:port`. Other types of upstream are specified as a table.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum UpstreamConfig {
+    /// Socket path or address of an upstream agent
+    Address(UpstreamAddress),
+    /// Socket path or address of an upstream agent, trusted without checking who owns it
+    Unverified { unverified: UpstreamAddress },
+    /// Keys held in memory by the mux itself and added with `ssh-add`, like OpenSSH `ssh-agent`
+    Keystore { keystore: KeystoreConfig },
+    /// Private keys loaded from files, which the mux signs with itself
+    KeyFiles { key_files: KeyFilesConfig },
+    /// Command speaking the SSH agent protocol on its standard input and output
+    Exec { exec: Vec<String> },
+    /// Command printing the socket path of an upstream agent
+    PathCommand { path_command: Vec<String> },
+    /// Glob pattern matching the sockets of any number of upstream agents
+    Glob { glob: PathBuf },
+    /// Candidate sockets for one upstream agent, chosen among at each refresh
+    Group { group: GroupConfig },
+}
+
+impl From<PathBuf> for UpstreamConfig {
+    fn from(value: PathBuf) -> Self {
+        Self::Address(value.into())
+    }
+}
+
+impl FromStr for UpstreamConfig {
+    type Err = AddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self::Address)
+    }
+}
+
+/// A configured upstream agent, ready to be connected to
+#[derive(Clone, Debug)]
+pub(crate) enum Upstream {
+    Agent(UpstreamAddress),
+    Unverified(UpstreamAddress),
+    Keystore(Keystore),
+    KeyFiles(KeyFiles),
+    Exec(ExecUpstream),
+    PathCommand(PathCommand),
+    Glob(PathBuf),
+    Group(GroupConfig),
+}
+
+impl Upstream {
+    pub fn new(config: UpstreamConfig) -> Self {
+        match config {
+            UpstreamConfig::Address(address) => Self::Agent(address),
+            UpstreamConfig::Unverified { unverified } => Self::Unverified(unverified),
+            UpstreamConfig::Keystore { keystore } => Self::Keystore(Keystore::new(keystore)),
+            UpstreamConfig::KeyFiles { key_files } => Self::KeyFiles(KeyFiles::new(key_files)),
+            UpstreamConfig::Exec { exec } => Self::Exec(ExecUpstream::new(exec)),
+            UpstreamConfig::PathCommand { path_command } => {
+                Self::PathCommand(PathCommand::new(path_command))
+            }
+            UpstreamConfig::Glob { glob } => Self::Glob(glob),
+            UpstreamConfig::Group { group } => Self::Group(group),
+        }
+    }
+
+    /// Connect to the upstream, checking its socket as `verify` requires unless it's configured
+    /// as unverified
+    pub async fn connect(&self, verify: Verify) -> Result<Box<dyn Session>, AgentError> {
+        match self {
+            Self::Agent(address) => address.connect(verify).await,
+            Self::Unverified(address) => address.connect(Verify::Nothing).await,
+            Self::Keystore(keystore) => Ok(Box::new(keystore.clone())),
+            Self::KeyFiles(key_files) => Ok(Box::new(key_files.clone())),
+            Self::Exec(exec) => exec.connect(),
+            Self::PathCommand(path_command) => path_command.connect(verify).await,
+            Self::Glob(_) | Self::Group(_) => Err(AgentError::Other(
+                format!("Upstream {} must be expanded before connecting", self).into(),
+            )),
+        }
+    }
+
+    /// Patterns matching the socket paths of this upstream, to watch for agents starting and
+    /// stopping
+    pub fn watch_patterns(&self) -> Vec<glob::Pattern> {
+        match self {
+            Self::Agent(UpstreamAddress::Unix(path))
+            | Self::Unverified(UpstreamAddress::Unix(path)) => path
+                .to_str()
+                .and_then(|p| glob::Pattern::new(&glob::Pattern::escape(p)).ok())
+                .into_iter()
+                .collect(),
+            Self::Glob(pattern) => pattern
+                .to_str()
+                .and_then(|p| glob::Pattern::new(p).ok())
+                .into_iter()
+                .collect(),
+            Self::Group(group) => group
+                .sockets
+                .iter()
+                .filter_map(|p| glob::Pattern::new(p.to_str()?).ok())
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Expand glob and group upstreams to the agent sockets currently matching them;
+    /// `listen_path` and its control socket are never included
+    pub fn expand(&self, listen_path: &Path) -> Vec<Self> {
+        let exclude = [listen_path.to_path_buf(), control_path(listen_path)];
+        let sockets = match self {
+            Self::Glob(pattern) => discovery::glob_sockets(pattern, &exclude),
+            Self::Group(group) => group.select(&exclude),
+            other => return vec![other.clone()],
+        };
+        sockets
+            .into_iter()
+            .map(|path| Self::Agent(path.into()))
+            .collect()
+    }
+
+    pub fn as_keystore(&self) -> Option<&Keystore> {
+        match self {
+            Self::Keystore(keystore) => Some(keystore),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Agent(address) | Self::Unverified(address) => address.fmt(f),
+            Self::Keystore(_) => f.write_str("built-in keystore"),
+            Self::KeyFiles(key_files) => key_files.fmt(f),
+            Self::Exec(exec) => exec.fmt(f),
+            Self::PathCommand(path_command) => path_command.fmt(f),
+            Self::Glob(pattern) => pattern.display().fmt(f),
+            Self::Group(group) => write!(f, "group {:?}", group.sockets),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Checks that an upstream agent socket belongs to the current user, so that another local user
+//! can't plant a fake agent to harvest signatures or inject keys
+
+use std::{
+    fs, io,
+    os::{fd::AsRawFd, unix::fs::MetadataExt},
+    path::Path,
+};
+
+use ssh_agent_lib::error::AgentError;
+
+/// Checks made before trusting an upstream agent's Unix socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Verify {
+    /// Explicitly trusted in the configuration
+    Nothing,
+    /// The socket and its directory must belong to the current user
+    Ownership,
+    /// As well as ownership, the agent listening on the socket must run as the current user
+    PeerCredentials,
+}
+
+/// Credentials of the process at the other end of a Unix socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Only available on Linux
+    pub pid: Option<i32>,
+}
+
+pub(crate) fn current_uid() -> u32 {
+    // SAFETY: getuid() is always successful
+    unsafe { libc::getuid() }
+}
+
+/// Whether `uid` can be trusted with the current user's keys; root can read them anyway
+fn is_trusted_uid(uid: u32) -> bool {
+    uid == current_uid() || uid == 0
+}
+
+fn refuse(what: impl std::fmt::Display, reason: String) -> AgentError {
+    AgentError::Other(format!("Refusing untrusted upstream agent {}: {}", what, reason).into())
+}
+
+/// Check that the socket at `path` and the directories containing it, before and after
+/// following symbolic links, are owned by the current user or root, and that nobody else could
+/// replace the socket
+///
+/// Only the directories immediately containing the socket are checked, not all their ancestors
+/// as OpenSSH does for its configuration files, since agent sockets usually live under shared
+/// directories like `/tmp`.
+pub(crate) fn check_socket(path: &Path) -> Result<(), AgentError> {
+    let real_path = fs::canonicalize(path)?;
+    let metadata = fs::metadata(&real_path)?;
+    if !is_trusted_uid(metadata.uid()) {
+        return Err(refuse(
+            path.display(),
+            format!("socket is owned by uid {}", metadata.uid()),
+        ));
+    }
+
+    let mut dirs = vec![];
+    // A bare file name has an empty parent, which is covered by the real path's parent
+    dirs.extend(path.parent().filter(|d| !d.as_os_str().is_empty()));
+    dirs.extend(real_path.parent().filter(|d| Some(*d) != path.parent()));
+    for dir in dirs {
+        let metadata = fs::metadata(dir)?;
+        if !is_trusted_uid(metadata.uid()) {
+            return Err(refuse(
+                path.display(),
+                format!("{} is owned by uid {}", dir.display(), metadata.uid()),
+            ));
+        }
+        // Like /tmp, directories writable by others are fine if only owners can remove files
+        let mode = metadata.mode();
+        if mode & 0o022 != 0 && mode & 0o1000 == 0 {
+            return Err(refuse(
+                path.display(),
+                format!("{} is writable by other users", dir.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the process listening at the other end of `socket` runs as the current user or
+/// root
+pub(crate) fn check_peer(
+    socket: &impl AsRawFd,
+    address: impl std::fmt::Display,
+) -> Result<(), AgentError> {
+    let peer = peer_credentials(socket)?;
+    if !is_trusted_uid(peer.uid) {
+        return Err(refuse(address, format!("agent runs as uid {}", peer.uid)));
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn peer_credentials(socket: &impl AsRawFd) -> io::Result<PeerCredentials> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len are valid for writes of the size passed
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        uid: cred.uid,
+        gid: cred.gid,
+        pid: Some(cred.pid),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn peer_credentials(socket: &impl AsRawFd) -> io::Result<PeerCredentials> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: uid and gid are valid for writes
+    let ret = unsafe { libc::getpeereid(socket.as_raw_fd(), &mut uid, &mut gid) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCredentials {
+        uid,
+        gid,
+        pid: None,
+    })
+}
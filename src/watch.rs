@@ -0,0 +1,91 @@
+//! Watching the directories of upstream agent sockets, to notice agents starting and stopping
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use glob::Pattern;
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+
+/// Watches the parent directories of upstream sockets, for as long as it's held
+pub(crate) struct SocketWatcher {
+    _watcher: RecommendedWatcher,
+}
+
+impl SocketWatcher {
+    /// Watch for sockets matching `patterns` appearing or disappearing, setting `stale` when they
+    /// do
+    ///
+    /// Only patterns whose parent directory is a literal path can be watched; sockets matching
+    /// other patterns are still found at each refresh, just not noticed in between.
+    pub fn start(patterns: Vec<Pattern>, stale: Arc<AtomicBool>) -> Option<Self> {
+        let dirs: BTreeSet<PathBuf> = patterns
+            .iter()
+            .filter_map(|p| watchable_dir(Path::new(p.as_str())))
+            .collect();
+        if dirs.is_empty() {
+            return None;
+        }
+
+        let mut watcher = match notify::recommended_watcher(move |event| {
+            handle_event(event, &patterns, &stale)
+        }) {
+            Ok(w) => w,
+            Err(e) => {
+                log::warn!("Not watching upstream agent sockets: {}", e);
+                return None;
+            }
+        };
+        for dir in dirs {
+            match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(()) => log::debug!("Watching {} for upstream agent sockets", dir.display()),
+                // Commonly because the directory doesn't exist (yet)
+                Err(e) => log::debug!("Not watching {}: {}", dir.display(), e),
+            }
+        }
+        Some(Self { _watcher: watcher })
+    }
+}
+
+/// The parent directory of `pattern`, if it doesn't contain any glob wildcards
+fn watchable_dir(pattern: &Path) -> Option<PathBuf> {
+    let dir = pattern.parent()?.to_str()?;
+    (!dir.is_empty() && Pattern::escape(dir) == dir).then(|| dir.into())
+}
+
+fn handle_event(event: notify::Result<Event>, patterns: &[Pattern], stale: &AtomicBool) {
+    let event = match event {
+        Ok(event) => event,
+        Err(e) => {
+            log::debug!("Error watching upstream agent sockets: {}", e);
+            return;
+        }
+    };
+    let appeared = match event.kind {
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => Some(true),
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => Some(false),
+        // Reported without knowing which side of the rename this path is on
+        EventKind::Modify(ModifyKind::Name(_)) => None,
+        _ => return,
+    };
+
+    for path in &event.paths {
+        if !patterns.iter().any(|p| p.matches_path(path)) {
+            continue;
+        }
+        if appeared.unwrap_or_else(|| path.exists()) {
+            log::info!("Upstream agent socket {} appeared", path.display());
+        } else {
+            log::info!("Upstream agent socket {} went away", path.display());
+        }
+        stale.store(true, Ordering::Relaxed);
+    }
+}
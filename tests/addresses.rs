@@ -0,0 +1,110 @@
+use std::{
+    ffi::OsString,
+    io,
+    net::{Shutdown, TcpListener},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    thread,
+};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Copy data in both directions between `client` and a new connection to `agent_sock`
+fn relay<S>(client: S, agent_sock: &Path)
+where
+    S: Send + Sync + 'static,
+    for<'a> &'a S: io::Read + io::Write,
+{
+    let Ok(agent) = UnixStream::connect(agent_sock) else {
+        return;
+    };
+    let agent_reader = agent.try_clone().unwrap();
+    let client = std::sync::Arc::new(client);
+    let client_writer = client.clone();
+    thread::spawn(move || {
+        let _ = io::copy(&mut &agent_reader, &mut &*client_writer);
+    });
+    let _ = io::copy(&mut &*client, &mut &agent);
+    let _ = agent.shutdown(Shutdown::Both);
+}
+
+/// Forward connections on a loopback TCP port to `agent_sock`, returning the port's address
+fn forward_tcp(agent_sock: PathBuf) -> io::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?.to_string();
+    thread::spawn(move || {
+        for client in listener.incoming().map_while(Result::ok) {
+            let agent_sock = agent_sock.clone();
+            thread::spawn(move || relay(client, &agent_sock));
+        }
+    });
+    Ok(address)
+}
+
+/// Forward connections on an abstract Unix socket to `agent_sock`
+#[cfg(target_os = "linux")]
+fn forward_abstract(name: &str, agent_sock: PathBuf) -> io::Result<()> {
+    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+    let listener = UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)?;
+    thread::spawn(move || {
+        for client in listener.incoming().map_while(Result::ok) {
+            let agent_sock = agent_sock.clone();
+            thread::spawn(move || relay(client, &agent_sock));
+        }
+    });
+    Ok(())
+}
+
+fn assert_signs_with_all_keys(mux_agent: &SshAgentInstance) -> TestResult {
+    let keys_in_agent = mux_agent.list()?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    for key in keys::PUBLIC {
+        assert!(keys_in_agent.iter().any(|k| k == key));
+        mux_agent.sign_and_verify(temp_dir.path(), key)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn tcp_upstream() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    for key in keys::PRIVATE {
+        upstream.add(key)?;
+    }
+    let address = forward_tcp(upstream.sock_path.to_path_buf())?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(r##"agent_sock_paths = ["tcp:{address}"]"##),
+        None::<OsString>,
+    )?;
+    assert_signs_with_all_keys(&mux_agent)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn abstract_and_unix_uri_upstreams() -> TestResult {
+    let abstract_upstream = SshAgentInstance::new_openssh()?;
+    abstract_upstream.add(keys::TEST_KEY_RSA)?;
+    abstract_upstream.add(keys::TEST_KEY_ECDSA)?;
+    let name = format!("ssh-agent-mux-test-{}", std::process::id());
+    forward_abstract(&name, abstract_upstream.sock_path.to_path_buf())?;
+
+    let unix_upstream = SshAgentInstance::new_openssh()?;
+    unix_upstream.add(keys::TEST_KEY_ED25519)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["unix-abstract:{}", "unix:{}"]"##,
+            name,
+            unix_upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_signs_with_all_keys(&mux_agent)
+}
@@ -0,0 +1,69 @@
+use std::{ffi::OsString, fs};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn mux_with_algorithms(
+    upstream: &SshAgentInstance,
+    algorithms: &str,
+) -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\"]\n[algorithms]\n{}",
+            upstream.sock_path.display(),
+            algorithms,
+        ),
+        None::<OsString>,
+    )
+}
+
+#[test]
+fn sha1_rsa_signatures() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_RSA)?;
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let public_key_path = dir.path().join("key.pub");
+    fs::write(&public_key_path, keys::TEST_KEY_RSA_PUB)?;
+    // `ssh-add -T` asks for a legacy ssh-rsa signature, and accepts any RSA signature
+    let test_key = [OsString::from("-T"), public_key_path.into()];
+
+    let mux_agent = mux_with_algorithms(&agent, "")?;
+    assert!(!mux_agent.ssh_add(&test_key)?);
+    // Only SHA-1 is refused
+    mux_agent.sign_and_verify(dir.path(), keys::TEST_KEY_RSA_PUB)?;
+
+    let mux_agent = mux_with_algorithms(&agent, r#"sha1_rsa = "upgrade""#)?;
+    assert!(mux_agent.ssh_add(&test_key)?);
+
+    Ok(())
+}
+
+#[test]
+fn disallowed_key_types_hidden() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_RSA)?;
+    agent.add(keys::TEST_KEY_ECDSA)?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+
+    let mux_agent = mux_with_algorithms(&agent, r#"deny_key_types = ["ssh-rsa"]"#)?;
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ECDSA_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_RSA_PUB)
+        .is_err());
+
+    let mux_agent = mux_with_algorithms(&agent, r#"key_types = ["ssh-ed25519", "ecdsa-*"]"#)?;
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ECDSA_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+
+    Ok(())
+}
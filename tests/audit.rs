@@ -0,0 +1,149 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use harness::SshAgentInstance;
+use serde_json::Value;
+use ssh_agent_lib::{
+    agent::Session,
+    client::Client,
+    proto::{PublicCredential, SignRequest},
+    ssh_key::PublicKey,
+};
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn signatures_audited() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    agent.add(keys::TEST_KEY_RSA)?;
+
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let audit_path = dir.path().join("audit.jsonl");
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"
+            agent_sock_paths = ["{}"]
+            audit_log = "{}"
+            "##,
+            agent.sock_path.display(),
+            audit_path.display(),
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.sign_and_verify(dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    // `ssh-add -T` asks for a SHA-1 signature, which is refused by default
+    let public_key_path = dir.path().join("rsa.pub");
+    fs::write(&public_key_path, keys::TEST_KEY_RSA_PUB)?;
+    assert!(!mux_agent.ssh_add([OsString::from("-T"), public_key_path.into()])?);
+
+    let records = read_records(&audit_path)?;
+    assert_eq!(records.len(), 2);
+
+    let signed = &records[0];
+    assert_eq!(signed["outcome"], "signed");
+    assert_eq!(signed["purpose"], "sshsig");
+    assert_eq!(signed["namespace"], "file");
+    assert_eq!(signed["comment"], "integration-test-ed25519");
+    assert_eq!(signed["upstream"], agent.sock_path.display().to_string());
+    assert!(signed["fingerprint"]
+        .as_str()
+        .is_some_and(|f| f.starts_with("SHA256:")));
+    assert!(signed["timestamp"].is_string());
+    assert!(signed["latency_ms"].is_number());
+    assert!(signed["client"]["uid"].is_number());
+
+    let refused = &records[1];
+    assert_eq!(refused["outcome"], "refused");
+    assert_eq!(refused["reason"], "SHA-1 signature");
+
+    Ok(())
+}
+
+fn read_records(path: &Path) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?)
+}
+
+#[tokio::test]
+async fn only_verified_host_keys_audited() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let audit_path = dir.path().join("audit.jsonl");
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"
+            agent_sock_paths = ["{}"]
+            audit_log = "{}"
+            "##,
+            agent.sock_path.display(),
+            audit_path.display(),
+        ),
+        None::<OsString>,
+    )?;
+    let mut client = Client::new(tokio::net::UnixStream::connect(&mux_agent.sock_path).await?);
+    let sign_request = SignRequest {
+        credential: PublicCredential::Key(
+            PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+                .key_data()
+                .clone(),
+        ),
+        data: b"data".to_vec(),
+        flags: 0,
+    };
+    let host_key = PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+        .fingerprint(Default::default())
+        .to_string();
+
+    assert!(client
+        .extension(harness::session_bind(
+            keys::TEST_KEY_ED25519,
+            b"another session ID"
+        )?)
+        .await
+        .is_err());
+    client.sign(sign_request.clone()).await?;
+    client
+        .extension(harness::session_bind(
+            keys::TEST_KEY_ED25519,
+            b"session ID",
+        )?)
+        .await?;
+    client.sign(sign_request.clone()).await?;
+    // The connection keeps its first binding
+    let other_host_key = dir.path().join("host_key");
+    duct::cmd!(
+        "ssh-keygen",
+        "-q",
+        "-t",
+        "ed25519",
+        "-N",
+        "",
+        "-f",
+        &other_host_key
+    )
+    .run()?;
+    assert!(client
+        .extension(harness::session_bind(
+            &fs::read_to_string(&other_host_key)?,
+            b"session ID"
+        )?)
+        .await
+        .is_err());
+    client.sign(sign_request).await?;
+
+    let records = read_records(&audit_path)?;
+    assert_eq!(records.len(), 3);
+    assert!(records[0]["host_key"].is_null());
+    assert_eq!(records[1]["host_key"], host_key.as_str());
+    assert_eq!(records[2]["host_key"], host_key.as_str());
+
+    Ok(())
+}
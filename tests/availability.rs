@@ -0,0 +1,43 @@
+use std::{ffi::OsString, thread, time::Duration};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn keys_unavailable_after_lifetime() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ECDSA)?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"agent_sock_paths = ["{}"]
+            [[availability]]
+            keys = ["{}"]
+            unlocked_lifetime = 1"#,
+            agent.sock_path.display(),
+            keys::TEST_KEY_ED25519_PUB.trim(),
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ECDSA_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    thread::sleep(Duration::from_millis(1200));
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ECDSA_PUB]);
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ECDSA_PUB)?;
+
+    Ok(())
+}
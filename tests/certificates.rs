@@ -0,0 +1,222 @@
+use std::{ffi::OsString, fs};
+
+use duct::cmd;
+use harness::{CertificateAuthority, SshAgentInstance};
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Compare public keys or certificates by algorithm and key blob, ignoring comments
+fn key_blob(line: &str) -> String {
+    line.split_whitespace()
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn agent_has(keys_in_agent: &[String], key: &str) -> bool {
+    keys_in_agent.iter().any(|k| key_blob(k) == key_blob(key))
+}
+
+fn make_openssh_agent_with_keys() -> std::io::Result<SshAgentInstance> {
+    let agent = SshAgentInstance::new_openssh()?;
+    for key in keys::PRIVATE {
+        agent.add(key)?;
+    }
+    Ok(agent)
+}
+
+#[test]
+fn expired_certificates_are_dropped() -> TestResult {
+    let ca = CertificateAuthority::new()?;
+    let valid_key = ca.issue(
+        "valid_ed25519",
+        keys::TEST_KEY_ED25519,
+        keys::TEST_KEY_ED25519_PUB,
+        "alice",
+        "-5m:+1h",
+    )?;
+    let expired_key = ca.issue(
+        "expired_ecdsa",
+        keys::TEST_KEY_ECDSA,
+        keys::TEST_KEY_ECDSA_PUB,
+        "alice",
+        "20000101:20000102",
+    )?;
+    let future_key = ca.issue(
+        "future_rsa",
+        keys::TEST_KEY_RSA,
+        keys::TEST_KEY_RSA_PUB,
+        "alice",
+        "+1d:+2d",
+    )?;
+
+    let upstream = SshAgentInstance::new_openssh()?;
+    for key in [&valid_key, &expired_key, &future_key] {
+        upstream.add_file(key)?;
+    }
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let keys_in_agent = mux_agent.list()?;
+    assert!(agent_has(&keys_in_agent, &ca.certificate(&valid_key)?));
+    assert!(!agent_has(&keys_in_agent, &ca.certificate(&expired_key)?));
+    assert!(!agent_has(&keys_in_agent, &ca.certificate(&future_key)?));
+    // Plain keys are still offered, even if their certificates aren't
+    for key in keys::PUBLIC {
+        assert!(agent_has(&keys_in_agent, key));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn certificates_filtered_by_principal() -> TestResult {
+    let ca = CertificateAuthority::new()?;
+    let alice_key = ca.issue(
+        "alice_ed25519",
+        keys::TEST_KEY_ED25519,
+        keys::TEST_KEY_ED25519_PUB,
+        "alice,deploy",
+        "-5m:+1h",
+    )?;
+    let bob_key = ca.issue(
+        "bob_ecdsa",
+        keys::TEST_KEY_ECDSA,
+        keys::TEST_KEY_ECDSA_PUB,
+        "bob",
+        "-5m:+1h",
+    )?;
+
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add_file(&alice_key)?;
+    upstream.add_file(&bob_key)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"
+            agent_sock_paths = ["{}"]
+
+            [certificates]
+            principals = ["deploy"]
+            "##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    let keys_in_agent = mux_agent.list()?;
+    assert!(agent_has(&keys_in_agent, &ca.certificate(&alice_key)?));
+    assert!(!agent_has(&keys_in_agent, &ca.certificate(&bob_key)?));
+
+    Ok(())
+}
+
+fn mux_with_attached_certificates(
+    upstream: &SshAgentInstance,
+    ca: &CertificateAuthority,
+    attach: &str,
+) -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        &format!(
+            r##"
+            agent_sock_paths = ["{}"]
+
+            [certificates]
+            paths = ["{}"]
+            attach = "{}"
+            "##,
+            upstream.sock_path.display(),
+            ca.dir.path().display(),
+            attach,
+        ),
+        None::<OsString>,
+    )
+}
+
+#[test]
+fn attached_certificate_offered_and_signs() -> TestResult {
+    let ca = CertificateAuthority::new()?;
+    let key_path = ca.issue(
+        "attached_ed25519",
+        keys::TEST_KEY_ED25519,
+        keys::TEST_KEY_ED25519_PUB,
+        "alice",
+        "-5m:+1h",
+    )?;
+    let cert = ca.certificate(&key_path)?;
+
+    // The upstream agent only holds the plain key
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add(keys::TEST_KEY_ED25519)?;
+    let mux_agent = mux_with_attached_certificates(&upstream, &ca, "alongside")?;
+
+    let keys_in_agent = mux_agent.list()?;
+    assert!(agent_has(&keys_in_agent, keys::TEST_KEY_ED25519_PUB));
+    assert!(agent_has(&keys_in_agent, &cert));
+
+    let message = ca.dir.path().join("message");
+    fs::write(&message, "signed with an attached certificate")?;
+    let mut cert_path = key_path.into_os_string();
+    cert_path.push("-cert.pub");
+    cmd!(
+        "ssh-keygen",
+        "-Y",
+        "sign",
+        "-n",
+        "file",
+        "-f",
+        &cert_path,
+        &message
+    )
+    .env("SSH_AUTH_SOCK", &mux_agent.sock_path)
+    .stdout_null()
+    .stderr_null()
+    .run()?;
+    let mut signature_path = message.clone().into_os_string();
+    signature_path.push(".sig");
+    let output = cmd!(
+        "ssh-keygen",
+        "-Y",
+        "check-novalidate",
+        "-n",
+        "file",
+        "-s",
+        &signature_path
+    )
+    .stdin_path(&message)
+    .stderr_to_stdout()
+    .read()?;
+    assert!(output.contains("ED25519-CERT"), "{output}");
+
+    Ok(())
+}
+
+#[test]
+fn attached_certificate_instead_of_key() -> TestResult {
+    let ca = CertificateAuthority::new()?;
+    let key_path = ca.issue(
+        "attached_ecdsa",
+        keys::TEST_KEY_ECDSA,
+        keys::TEST_KEY_ECDSA_PUB,
+        "alice",
+        "-5m:+1h",
+    )?;
+
+    let upstream = make_openssh_agent_with_keys()?;
+    let mux_agent = mux_with_attached_certificates(&upstream, &ca, "instead")?;
+
+    let keys_in_agent = mux_agent.list()?;
+    assert!(agent_has(&keys_in_agent, &ca.certificate(&key_path)?));
+    assert!(!agent_has(&keys_in_agent, keys::TEST_KEY_ECDSA_PUB));
+    assert!(agent_has(&keys_in_agent, keys::TEST_KEY_RSA_PUB));
+    assert!(agent_has(&keys_in_agent, keys::TEST_KEY_ED25519_PUB));
+
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::ffi::OsString;
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+#[cfg(target_os = "linux")]
+fn keys_restricted_by_client_program() -> TestResult {
+    let ed25519_agent = SshAgentInstance::new_openssh()?;
+    ed25519_agent.add(keys::TEST_KEY_ED25519)?;
+    let rsa_agent = SshAgentInstance::new_openssh()?;
+    rsa_agent.add(keys::TEST_KEY_RSA)?;
+
+    // `ssh-add` lists keys, and `ssh-keygen` signs with them
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"
+            agent_sock_paths = ["{}", "{}"]
+
+            [[client_policies]]
+            programs = ["ssh-add"]
+            keys = ["{}"]
+
+            [[client_policies]]
+            deny_upstreams = ["{}"]
+            "##,
+            ed25519_agent.sock_path.display(),
+            rsa_agent.sock_path.display(),
+            keys::TEST_KEY_RSA_PUB,
+            ed25519_agent.sock_path.display(),
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_RSA_PUB)?;
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use std::{
+    ffi::OsString,
+    fs,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use harness::SshAgentInstance;
+use ssh_agent_mux::{control_path, control_request, ControlRequest};
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn control_socket_requests() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let upstream = agent.sock_path.display().to_string();
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!("agent_sock_paths = [\"{upstream}\"]"),
+        None::<OsString>,
+    )?;
+    let control = control_path(&mux_agent.sock_path);
+    let request = |request| control_request(&control, &request);
+
+    let response = request(ControlRequest::Refresh)?;
+    assert!(response.ok);
+    let keys = response.keys.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].upstream, upstream);
+    assert!(keys::TEST_KEY_ED25519_PUB.starts_with(&keys[0].public_key));
+
+    let status = request(ControlRequest::Status)?.status.unwrap();
+    assert_eq!(
+        (status.upstreams, status.healthy_upstreams, status.keys),
+        (1, 1, 1)
+    );
+    assert!(!status.locked);
+
+    let upstreams = request(ControlRequest::Upstreams)?.upstreams.unwrap();
+    assert_eq!(upstreams.len(), 1);
+    assert!(upstreams[0].enabled && upstreams[0].healthy);
+    assert_eq!(upstreams[0].keys, 1);
+
+    // Disabling an upstream hides its keys
+    assert!(
+        request(ControlRequest::Disable {
+            upstream: upstream.clone()
+        })?
+        .ok
+    );
+    assert_eq!(mux_agent.list()?, Vec::<String>::new());
+    assert!(!request(ControlRequest::Upstreams)?.upstreams.unwrap()[0].enabled);
+    assert!(
+        request(ControlRequest::Enable {
+            upstream: upstream.clone()
+        })?
+        .ok
+    );
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    let response = request(ControlRequest::Disable {
+        upstream: "/nonexistent.sock".into(),
+    })?;
+    assert!(!response.ok && response.error.is_some());
+
+    assert!(
+        request(ControlRequest::Lock {
+            passphrase: "secret".into()
+        })?
+        .ok
+    );
+    assert!(request(ControlRequest::Status)?.status.unwrap().locked);
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    assert!(
+        request(ControlRequest::Unlock {
+            passphrase: "secret".into()
+        })?
+        .ok
+    );
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    assert!(
+        request(ControlRequest::LogLevel {
+            level: "debug".into()
+        })?
+        .ok
+    );
+    assert!(
+        !request(ControlRequest::LogLevel {
+            level: "loud".into()
+        })?
+        .ok
+    );
+
+    Ok(())
+}
+
+/// Run the mux listening on `listen_path`, and whether it exits, failing, within a few seconds
+fn mux_fails_to_start(listen_path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    let handle = duct::cmd!(
+        env!(concat!("CARGO_BIN_EXE_", env!("CARGO_PKG_NAME"))),
+        "--config=/nonexistent",
+        "--listen",
+        listen_path
+    )
+    .unchecked()
+    .stdout_null()
+    .stderr_null()
+    .start()?;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if let Some(output) = handle.try_wait()? {
+            return Ok(!output.status.success());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    handle.kill()?;
+    Ok(false)
+}
+
+#[test]
+fn control_socket_never_replaces_other_files() -> TestResult {
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let listen_path = temp_dir.path().join("agent.sock");
+    let control = control_path(&listen_path);
+    fs::write(&control, "not a socket")?;
+
+    assert!(mux_fails_to_start(&listen_path)?);
+    assert_eq!(fs::read_to_string(&control)?, "not a socket");
+
+    // The control socket would be the listen socket
+    assert!(mux_fails_to_start(&temp_dir.path().join("agent.ctl"))?);
+
+    Ok(())
+}
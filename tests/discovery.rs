@@ -0,0 +1,204 @@
+use std::{
+    ffi::OsString,
+    fs,
+    os::unix::{fs::symlink, net::UnixListener},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use duct::cmd;
+use harness::SshAgentInstance;
+use ssh_agent_mux::control_path;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Link `target` into `dir` as `<subdir>/<name>`, like an agent socket forwarded by sshd
+fn link_socket(dir: &Path, subdir: &str, name: &str, target: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir.join(subdir))?;
+    symlink(target, dir.join(subdir).join(name))
+}
+
+#[test]
+fn glob_upstreams_discovered_at_each_refresh() -> TestResult {
+    let rsa_agent = SshAgentInstance::new_openssh()?;
+    rsa_agent.add(keys::TEST_KEY_RSA)?;
+    let ecdsa_agent = SshAgentInstance::new_openssh()?;
+    ecdsa_agent.add(keys::TEST_KEY_ECDSA)?;
+    let ed25519_agent = SshAgentInstance::new_openssh()?;
+    ed25519_agent.add(keys::TEST_KEY_ED25519)?;
+
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    link_socket(dir.path(), "ssh-a", "agent.1", &rsa_agent.sock_path)?;
+    link_socket(dir.path(), "ssh-b", "agent.2", &ecdsa_agent.sock_path)?;
+    // Files that aren't sockets are skipped
+    fs::create_dir(dir.path().join("ssh-c"))?;
+    fs::write(dir.path().join("ssh-c").join("agent.3"), "not a socket")?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ glob = "{}/ssh-*/agent.*" }}]"##,
+            dir.path().display()
+        ),
+        None::<OsString>,
+    )?;
+    // The mux's own socket is never an upstream
+    link_socket(dir.path(), "ssh-0", "agent.self", &mux_agent.sock_path)?;
+
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_RSA_PUB, keys::TEST_KEY_ECDSA_PUB]
+    );
+
+    // Sockets appearing later are picked up without reloading the configuration
+    link_socket(dir.path(), "ssh-d", "agent.4", &ed25519_agent.sock_path)?;
+    assert_eq!(mux_agent.list()?, keys::PUBLIC);
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    fs::remove_dir_all(dir.path().join("ssh-a"))?;
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ECDSA_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn own_sockets_never_upstreams() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    // A glob matching every socket in the directory that the mux listens in, which has the
+    // mux's control socket too; the upstream sorts last, so a group would try the mux first
+    let glob = format!("{}/*", dir.path().display());
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ glob = "{glob}" }}, {{ group = {{ sockets = ["{glob}"] }} }}]"##
+        ),
+        None::<OsString>,
+    )?;
+    symlink(&mux_agent.sock_path, dir.path().join("mux.sock"))?;
+    symlink(
+        control_path(&mux_agent.sock_path),
+        dir.path().join("mux.ctl"),
+    )?;
+    symlink(&agent.sock_path, dir.path().join("upstream.sock"))?;
+
+    // Once through the glob, and once through the group
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+    let (ok, output) = mux_agent.mux_command(["status", "--json"])?;
+    assert!(ok);
+    let status: serde_json::Value = serde_json::from_str(&output)?;
+    assert_eq!(status["status"]["upstreams"], 2);
+
+    Ok(())
+}
+
+fn mux_with_group(dir: &Path, strategy: &str) -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ group = {{ sockets = ["{0}/dead.sock", "{0}/ssh-*/agent.*"], strategy = "{1}" }} }}]"##,
+            dir.display(),
+            strategy
+        ),
+        None::<OsString>,
+    )
+}
+
+/// Two agents, linked as `ssh-a/agent.1` and `ssh-b/agent.2`, where the first is newer; and a
+/// socket that no agent is listening on, which is the first candidate
+fn make_group_agents(dir: &Path) -> std::io::Result<[SshAgentInstance; 2]> {
+    let older = SshAgentInstance::new_openssh()?;
+    older.add(keys::TEST_KEY_RSA)?;
+    let newer = SshAgentInstance::new_openssh()?;
+    newer.add(keys::TEST_KEY_ED25519)?;
+    cmd!("touch", "-t", "200001010000", &older.sock_path).run()?;
+
+    link_socket(dir, "ssh-a", "agent.1", &newer.sock_path)?;
+    link_socket(dir, "ssh-b", "agent.2", &older.sock_path)?;
+    drop(UnixListener::bind(dir.join("dead.sock"))?);
+    Ok([older, newer])
+}
+
+#[test]
+fn group_first_alive() -> TestResult {
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let [_older, newer] = make_group_agents(dir.path())?;
+    let mux_agent = mux_with_group(dir.path(), "first-alive")?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    // Falls back to the next candidate when the selected agent goes away
+    drop(newer);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+
+    Ok(())
+}
+
+#[test]
+fn group_newest() -> TestResult {
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let [older, _newer] = make_group_agents(dir.path())?;
+    let mux_agent = mux_with_group(dir.path(), "newest")?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    // A reconnection creates a newer socket
+    cmd!("touch", &older.sock_path).run()?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_RSA_PUB)?;
+
+    Ok(())
+}
+
+#[test]
+fn group_all() -> TestResult {
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let _agents = make_group_agents(dir.path())?;
+    let mux_agent = mux_with_group(dir.path(), "all")?;
+
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_RSA_PUB]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn socket_changes_invalidate_known_keys() -> TestResult {
+    let first = SshAgentInstance::new_openssh()?;
+    first.add(keys::TEST_KEY_ED25519)?;
+    let second = SshAgentInstance::new_openssh()?;
+    second.add(keys::TEST_KEY_ED25519)?;
+
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    symlink(&first.sock_path, dir.path().join("a.sock"))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{0}/a.sock", "{0}/b.sock"]"##,
+            dir.path().display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    // The key moves to another upstream; without noticing, the mux would still send signing
+    // requests to the first, which is gone. `ssh-add -T` signs without listing keys first.
+    fs::remove_file(dir.path().join("a.sock"))?;
+    symlink(&second.sock_path, dir.path().join("b.sock"))?;
+    thread::sleep(Duration::from_millis(200));
+    let public_key_path = dir.path().join("key.pub");
+    fs::write(&public_key_path, keys::TEST_KEY_ED25519_PUB)?;
+    assert!(mux_agent.ssh_add([OsString::from("-T"), public_key_path.into()])?);
+
+    Ok(())
+}
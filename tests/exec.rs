@@ -0,0 +1,93 @@
+use std::{ffi::OsString, fs, path::Path, thread, time::Duration};
+
+use duct::cmd;
+use harness::SshAgentInstance;
+use tempfile::TempDir;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Relays between standard input and output and the agent socket given as its first argument,
+/// like `socat - UNIX-CONNECT:<socket>`; each relay's pid is appended to its second argument
+const RELAY_SCRIPT: &str = r#"
+import os, socket, sys, threading
+
+with open(sys.argv[2], "a") as pids:
+    pids.write(f"{os.getpid()}\n")
+agent = socket.socket(socket.AF_UNIX)
+agent.connect(sys.argv[1])
+
+def pump():
+    while data := os.read(0, 65536):
+        agent.sendall(data)
+    agent.shutdown(socket.SHUT_WR)
+
+threading.Thread(target=pump, daemon=True).start()
+while data := agent.recv(65536):
+    os.write(1, data)
+"#;
+
+fn make_exec_mux(upstream: &SshAgentInstance) -> std::io::Result<(SshAgentInstance, TempDir)> {
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let script_path = dir.path().join("relay.py");
+    fs::write(&script_path, RELAY_SCRIPT)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ exec = ["python3", "{}", "{}", "{}"] }}]"##,
+            script_path.display(),
+            upstream.sock_path.display(),
+            dir.path().join("pids").display(),
+        ),
+        None::<OsString>,
+    )?;
+    Ok((mux_agent, dir))
+}
+
+fn relay_pids(dir: &Path) -> Vec<String> {
+    fs::read_to_string(dir.join("pids"))
+        .map(|pids| pids.lines().map(Into::into).collect())
+        .unwrap_or_default()
+}
+
+#[test]
+fn exec_upstream_lists_and_signs() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    for key in keys::PRIVATE {
+        upstream.add(key)?;
+    }
+    let (mux_agent, dir) = make_exec_mux(&upstream)?;
+
+    let keys_in_agent = mux_agent.list()?;
+    assert_eq!(keys_in_agent, keys::PUBLIC);
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    for key in keys::PUBLIC {
+        mux_agent.sign_and_verify(temp_dir.path(), key)?;
+    }
+
+    // Every request went through the same child process
+    assert_eq!(relay_pids(dir.path()).len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn exec_upstream_restarted() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add(keys::TEST_KEY_ED25519)?;
+    let (mux_agent, dir) = make_exec_mux(&upstream)?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    let pids = relay_pids(dir.path());
+    cmd!("kill", &pids[0]).run()?;
+    thread::sleep(Duration::from_millis(200));
+    // Not restarted until after the backoff delay
+    assert!(mux_agent.list()?.is_empty());
+
+    thread::sleep(Duration::from_millis(1200));
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(relay_pids(dir.path()).len(), 2);
+
+    Ok(())
+}
@@ -2,11 +2,18 @@ use std::{
     ffi::{OsStr, OsString},
     fs,
     io::{self, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
 use duct::{cmd, unix::HandleExt, Handle};
-use tempfile::TempPath;
+use rsa::signature::Signer;
+use ssh_agent_lib::{
+    proto::{extension::SessionBind, Extension},
+    ssh_key::PrivateKey,
+};
+use tempfile::{TempDir, TempPath};
 
 const CRATE_MAIN_BIN: &str = env!(concat!("CARGO_BIN_EXE_", env!("CARGO_PKG_NAME")));
 const AGENT_TIMEOUT: Duration = Duration::from_secs(2);
@@ -14,6 +21,7 @@ const AGENT_POLL: Duration = Duration::from_micros(100);
 const SIGTERM: std::ffi::c_int = 15;
 
 pub enum SshAgentType {
+    #[allow(dead_code)]
     OpenSsh,
     Mux,
 }
@@ -70,7 +78,9 @@ impl SshAgentInstance {
             })
             .start()?;
         let agent_start_time = Instant::now();
-        while !sock_path.exists() {
+        // The socket file exists as soon as it's bound, but connections are refused until the
+        // agent starts listening
+        while UnixStream::connect(&sock_path).is_err() {
             std::thread::sleep(AGENT_POLL);
             if agent_start_time.elapsed() >= AGENT_TIMEOUT {
                 return Err(io::Error::new(
@@ -83,6 +93,7 @@ impl SshAgentInstance {
         Ok(Self { handle, sock_path })
     }
 
+    #[allow(dead_code)]
     pub fn new_openssh() -> io::Result<Self> {
         Self::new(SshAgentType::OpenSsh, None::<&OsStr>)
             .map_err(|e| map_binary_notfound_error("ssh-agent", e))
@@ -106,6 +117,7 @@ impl SshAgentInstance {
             .map_err(|e| map_binary_notfound_error(CRATE_MAIN_BIN, e))
     }
 
+    #[allow(dead_code)]
     pub fn add(&self, key: &str) -> io::Result<()> {
         // Add an ssh-key from stdin
         cmd!("ssh-add", "-q", "--", "-")
@@ -117,6 +129,120 @@ impl SshAgentInstance {
         Ok(())
     }
 
+    #[allow(dead_code)]
+    pub fn add_file(&self, key_path: &Path) -> io::Result<()> {
+        // ssh-add also adds a certificate found at <key_path>-cert.pub
+        cmd!("ssh-add", "-q", "--", key_path)
+            .env("SSH_AUTH_SOCK", &self.sock_path)
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-add", e))?;
+
+        Ok(())
+    }
+
+    /// Sign a message with `public_key` using `ssh-keygen -Y sign`, and verify the signature;
+    /// files are written to `dir`
+    #[allow(dead_code)]
+    pub fn sign_and_verify(&self, dir: &Path, public_key: &str) -> io::Result<()> {
+        let key_path = dir.join("signing_key.pub");
+        let message_path = dir.join("message");
+        let allowed_signers_path = dir.join("allowed_signers");
+        fs::write(&key_path, public_key)?;
+        fs::write(&message_path, "message to sign")?;
+        fs::write(&allowed_signers_path, format!("signer {public_key}"))?;
+
+        cmd!(
+            "ssh-keygen",
+            "-Y",
+            "sign",
+            "-n",
+            "file",
+            "-f",
+            &key_path,
+            &message_path
+        )
+        .env("SSH_AUTH_SOCK", &self.sock_path)
+        .stdout_null()
+        .stderr_null()
+        .run()
+        .map_err(|e| map_binary_notfound_error("ssh-keygen", e))?;
+        let mut signature_path = message_path.clone().into_os_string();
+        signature_path.push(".sig");
+        let output = cmd!(
+            "ssh-keygen",
+            "-Y",
+            "verify",
+            "-f",
+            &allowed_signers_path,
+            "-I",
+            "signer",
+            "-n",
+            "file",
+            "-s",
+            &signature_path
+        )
+        .stdin_path(&message_path)
+        .stderr_to_stdout()
+        .stdout_capture()
+        .unchecked()
+        .run()?;
+        fs::remove_file(&signature_path)?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "signature verification failed: {}",
+                String::from_utf8_lossy(&output.stdout)
+            )))
+        }
+    }
+
+    /// Run `ssh-agent-mux` with `args`, to inspect this mux, returning whether it succeeded and
+    /// its output
+    #[allow(dead_code)]
+    pub fn mux_command<I, A>(&self, args: I) -> io::Result<(bool, String)>
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<OsString>,
+    {
+        let mut mux_args: Vec<OsString> = vec![
+            "--config=/nonexistent".into(),
+            "--listen".into(),
+            self.sock_path.as_os_str().into(),
+        ];
+        mux_args.extend(args.into_iter().map(Into::into));
+        let output = duct::cmd(CRATE_MAIN_BIN, mux_args)
+            .stdin_null()
+            .stdout_capture()
+            .stderr_null()
+            .unchecked()
+            .run()?;
+        Ok((
+            output.status.success(),
+            String::from_utf8(output.stdout).map_err(io::Error::other)?,
+        ))
+    }
+
+    /// Run `ssh-add` with `args` against this agent, returning whether it succeeded
+    #[allow(dead_code)]
+    pub fn ssh_add<I, A>(&self, args: I) -> io::Result<bool>
+    where
+        I: IntoIterator<Item = A>,
+        A: Into<OsString>,
+    {
+        let output = duct::cmd("ssh-add", args)
+            .env("SSH_AUTH_SOCK", &self.sock_path)
+            .stdin_null()
+            .stdout_null()
+            .stderr_null()
+            .unchecked()
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-add", e))?;
+        Ok(output.status.success())
+    }
+
+    #[allow(dead_code)]
     pub fn list(&self) -> io::Result<Vec<String>> {
         let output = cmd!("ssh-add", "-L")
             .env("SSH_AUTH_SOCK", &self.sock_path)
@@ -173,3 +299,85 @@ impl Drop for SshAgentInstance {
         );
     }
 }
+
+/// A throwaway OpenSSH certificate authority, for issuing certificates to test keys
+#[allow(dead_code)]
+pub struct CertificateAuthority {
+    pub dir: TempDir,
+    ca_key: PathBuf,
+}
+
+#[allow(dead_code)]
+impl CertificateAuthority {
+    pub fn new() -> io::Result<Self> {
+        let dir = tempfile::Builder::new()
+            .prefix("ca_")
+            .tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+        let ca_key = dir.path().join("ca");
+        cmd!("ssh-keygen", "-q", "-t", "ed25519", "-N", "", "-f", &ca_key)
+            .stdout_null()
+            .run()
+            .map_err(|e| map_binary_notfound_error("ssh-keygen", e))?;
+
+        Ok(Self { dir, ca_key })
+    }
+
+    /// Write `private_key` to a file named `name`, and issue a certificate for it with the given
+    /// principals and validity interval (in `ssh-keygen -V` syntax); returns the private key path
+    pub fn issue(
+        &self,
+        name: &str,
+        private_key: &str,
+        public_key: &str,
+        principals: &str,
+        validity: &str,
+    ) -> io::Result<PathBuf> {
+        let key_path = self.dir.path().join(name);
+        let pub_path = key_path.with_extension("pub");
+        fs::write(&key_path, private_key)?;
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600))?;
+        fs::write(&pub_path, public_key)?;
+        cmd!(
+            "ssh-keygen",
+            "-q",
+            "-s",
+            &self.ca_key,
+            "-I",
+            name,
+            "-n",
+            principals,
+            "-V",
+            validity,
+            &pub_path
+        )
+        .stdout_null()
+        .stderr_null()
+        .run()
+        .map_err(|e| map_binary_notfound_error("ssh-keygen", e))?;
+
+        Ok(key_path)
+    }
+
+    /// Public certificate issued for the key at `key_path`, in `authorized_keys` format
+    pub fn certificate(&self, key_path: &Path) -> io::Result<String> {
+        let mut cert_path = key_path.as_os_str().to_owned();
+        cert_path.push("-cert.pub");
+        Ok(fs::read_to_string(cert_path)?.trim().to_string())
+    }
+}
+
+/// A `session-bind@openssh.com` request from the server with `host_key`, which signs `signed`
+/// rather than the session ID if they differ
+#[allow(dead_code)]
+pub fn session_bind(
+    host_key: &str,
+    signed: &[u8],
+) -> Result<Extension, Box<dyn std::error::Error>> {
+    let host_key = PrivateKey::from_openssh(host_key)?;
+    Ok(Extension::new_message(SessionBind {
+        host_key: host_key.public_key().key_data().clone(),
+        session_id: b"session ID".to_vec(),
+        signature: host_key.try_sign(signed)?,
+        is_forwarding: false,
+    })?)
+}
@@ -0,0 +1,61 @@
+use std::{ffi::OsString, fs, path::Path, thread, time::Duration};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn attempts(dir: &Path) -> usize {
+    fs::read_to_string(dir.join("attempts")).map_or(0, |i| i.lines().count())
+}
+
+#[test]
+fn failing_upstreams_backed_off() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+
+    // A command that never finds a socket, counting how often the mux tries it
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ path_command = ["sh", "-c", "echo >> '{}/attempts'; exit 1"] }}, "{}"]"##,
+            dir.path().display(),
+            agent.sock_path.display(),
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(attempts(dir.path()), 1);
+
+    // Retried once the backoff has passed
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(attempts(dir.path()), 2);
+
+    Ok(())
+}
+
+#[test]
+fn upstream_failing_to_list_keys_skipped() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+
+    // Starts, but exits without answering
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ exec = ["true"] }}, "{}"]"##,
+            agent.sock_path.display(),
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::{ffi::OsString, fs, thread, time::Duration};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn hooks_run_on_events() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let hook_log = temp_dir.path().join("hook.log");
+    let stdin_log = temp_dir.path().join("stdin.log");
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"agent_sock_paths = ["{sock}"]
+            [[purpose_policies]]
+            keys = ["{rsa}"]
+            namespaces = ["git"]
+            [[hooks]]
+            events = ["sign-requested", "sign-completed", "sign-failed", "key-added"]
+            command = ["sh", "-c", "echo $SSH_AGENT_MUX_EVENT $SSH_AGENT_MUX_NAMESPACE $SSH_AGENT_MUX_COMMENT >> {log}"]
+            [[hooks]]
+            events = ["sign-completed"]
+            command = ["sh", "-c", "cat >> {stdin}"]
+            timeout = 5"#,
+            sock = agent.sock_path.display(),
+            rsa = keys::TEST_KEY_RSA_PUB.trim(),
+            log = hook_log.display(),
+            stdin = stdin_log.display(),
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    agent.add(keys::TEST_KEY_RSA)?;
+    // Refused by the purpose policy
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_RSA_PUB)
+        .is_err());
+    // Keys that an upstream agent stops listing for a while aren't new when they're back
+    let ed25519_path = temp_dir.path().join("ed25519.pub");
+    fs::write(&ed25519_path, keys::TEST_KEY_ED25519_PUB)?;
+    duct::cmd!("ssh-add", "-q", "-d", &ed25519_path)
+        .env("SSH_AUTH_SOCK", &agent.sock_path)
+        .run()?;
+    assert_eq!(mux_agent.list()?.len(), 1);
+    agent.add(keys::TEST_KEY_ED25519)?;
+    assert_eq!(mux_agent.list()?.len(), 2);
+
+    thread::sleep(Duration::from_millis(500));
+    let mut events: Vec<_> = fs::read_to_string(&hook_log)?
+        .lines()
+        .map(String::from)
+        .collect();
+    // Hooks run in the background, so may finish in any order
+    events.sort();
+    assert_eq!(
+        events,
+        [
+            "key-added integration-test-rsa",
+            "sign-completed file integration-test-ed25519",
+            "sign-failed file integration-test-rsa",
+            "sign-requested file integration-test-ed25519",
+        ]
+    );
+
+    let completed: serde_json::Value = serde_json::from_str(&fs::read_to_string(&stdin_log)?)?;
+    assert_eq!(completed["event"], "sign-completed");
+    assert_eq!(completed["outcome"], "signed");
+    assert_eq!(completed["upstream"], agent.sock_path.display().to_string());
+
+    Ok(())
+}
+
+fn mux_with_gating_hook(
+    upstream: &SshAgentInstance,
+    hook: &str,
+) -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\"]\n[[hooks]]\ngating = true\n{}",
+            upstream.sock_path.display(),
+            hook,
+        ),
+        None::<OsString>,
+    )
+}
+
+#[test]
+fn gating_hooks_allow_or_deny() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    agent.add(keys::TEST_KEY_ECDSA)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    let mux_agent = mux_with_gating_hook(
+        &agent,
+        r#"command = ["sh", "-c", "test \"$SSH_AGENT_MUX_COMMENT\" = integration-test-ed25519"]"#,
+    )?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ECDSA_PUB)
+        .is_err());
+
+    // Requests are denied when the hook takes too long, unless configured otherwise
+    let mux_agent = mux_with_gating_hook(&agent, "command = [\"sleep\", \"5\"]\ntimeout = 1")?;
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    let mux_agent = mux_with_gating_hook(
+        &agent,
+        "command = [\"sleep\", \"5\"]\ntimeout = 1\non_timeout = \"allow\"",
+    )?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use std::{ffi::OsString, fs};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn subcommands_inspect_running_mux() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let upstream = agent.sock_path.display().to_string();
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let key_path = temp_dir.path().join("key.pub");
+    fs::write(&key_path, keys::TEST_KEY_ED25519_PUB)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!("agent_sock_paths = [\"{upstream}\"]"),
+        None::<OsString>,
+    )?;
+
+    // Keys are listed from upstream agents even if no client has asked yet
+    let (ok, output) = mux_agent.mux_command(["keys"])?;
+    assert!(ok);
+    assert!(output.contains("integration-test-ed25519 (ssh-ed25519)"));
+    assert!(output.contains(&format!("from {upstream} (healthy)")));
+
+    let (ok, output) = mux_agent.mux_command([OsString::from("which"), key_path.into()])?;
+    assert!(ok);
+    assert_eq!(output, format!("{upstream} (healthy)\n"));
+
+    let (ok, output) = mux_agent.mux_command(["which", "SHA256:unknown"])?;
+    assert!(!ok);
+    assert_eq!(output, "");
+
+    let (ok, output) = mux_agent.mux_command(["status", "--json"])?;
+    assert!(ok);
+    let status: serde_json::Value = serde_json::from_str(&output)?;
+    assert_eq!(status["status"]["keys"], 1);
+    assert_eq!(status["upstreams"][0]["name"], upstream.as_str());
+    assert_eq!(status["upstreams"][0]["healthy"], true);
+
+    let (ok, output) = mux_agent.mux_command(["refresh", "--json"])?;
+    assert!(ok);
+    let keys: serde_json::Value = serde_json::from_str(&output)?;
+    assert_eq!(keys[0]["comment"], "integration-test-ed25519");
+    assert_eq!(keys[0]["upstream"], upstream.as_str());
+    assert_eq!(keys[0]["health"], "healthy");
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::{
+    ffi::OsString,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use duct::cmd;
+use harness::SshAgentInstance;
+use tempfile::TempDir;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+const PASSPHRASE: &str = "correct horse battery staple";
+
+/// Compare public keys by algorithm and key blob, ignoring comments
+fn key_blob(line: &str) -> String {
+    line.split_whitespace()
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Write the test keys to `id_*` files, encrypting the Ed25519 key with [`PASSPHRASE`]
+fn write_key_files() -> std::io::Result<TempDir> {
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    for (name, key, public_key) in [
+        ("id_rsa", keys::TEST_KEY_RSA, keys::TEST_KEY_RSA_PUB),
+        ("id_ecdsa", keys::TEST_KEY_ECDSA, keys::TEST_KEY_ECDSA_PUB),
+        (
+            "id_ed25519",
+            keys::TEST_KEY_ED25519,
+            keys::TEST_KEY_ED25519_PUB,
+        ),
+    ] {
+        let key_path = dir.path().join(name);
+        fs::write(&key_path, key)?;
+        fs::set_permissions(&key_path, fs::Permissions::from_mode(0o600))?;
+        fs::write(dir.path().join(format!("{name}.pub")), public_key)?;
+    }
+    cmd!(
+        "ssh-keygen",
+        "-q",
+        "-p",
+        "-P",
+        "",
+        "-N",
+        PASSPHRASE,
+        "-f",
+        dir.path().join("id_ed25519")
+    )
+    .stdout_null()
+    .run()?;
+    Ok(dir)
+}
+
+/// Write an askpass script printing `passphrase`, which records each prompt in `prompts`
+fn write_askpass(dir: &Path, passphrase: &str) -> std::io::Result<PathBuf> {
+    let askpass_path = dir.join("askpass");
+    fs::write(
+        &askpass_path,
+        format!(
+            "#!/bin/sh\necho \"$1\" >> '{}'\necho '{passphrase}'\n",
+            dir.join("prompts").display()
+        ),
+    )?;
+    fs::set_permissions(&askpass_path, fs::Permissions::from_mode(0o700))?;
+    Ok(askpass_path)
+}
+
+fn prompt_count(dir: &Path) -> usize {
+    fs::read_to_string(dir.join("prompts")).map_or(0, |p| p.lines().count())
+}
+
+fn make_key_files_mux(key_dir: &Path, askpass: &Path) -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ key_files = {{ paths = ["{}/id_*"], askpass = "{}" }} }}]"##,
+            key_dir.display(),
+            askpass.display()
+        ),
+        None::<OsString>,
+    )
+}
+
+#[test]
+fn sign_with_key_files() -> TestResult {
+    let key_dir = write_key_files()?;
+    let askpass = write_askpass(key_dir.path(), PASSPHRASE)?;
+    let mux_agent = make_key_files_mux(key_dir.path(), &askpass)?;
+
+    // Encrypted keys are listed without prompting, and `*.pub` files aren't mistaken for keys
+    let keys_in_agent = mux_agent.list()?;
+    assert_eq!(keys_in_agent.len(), keys::PUBLIC.len());
+    for key in keys::PUBLIC {
+        assert!(keys_in_agent.iter().any(|k| key_blob(k) == key_blob(key)));
+    }
+    assert_eq!(prompt_count(key_dir.path()), 0);
+
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    for key in keys::PUBLIC {
+        mux_agent.sign_and_verify(temp_dir.path(), key)?;
+    }
+    assert_eq!(prompt_count(key_dir.path()), 1);
+
+    // The decrypted key is cached
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    assert_eq!(prompt_count(key_dir.path()), 1);
+
+    Ok(())
+}
+
+#[test]
+fn wrong_passphrase_refused() -> TestResult {
+    let key_dir = write_key_files()?;
+    let askpass = write_askpass(key_dir.path(), "wrong passphrase")?;
+    let mux_agent = make_key_files_mux(key_dir.path(), &askpass)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ECDSA_PUB)?;
+
+    Ok(())
+}
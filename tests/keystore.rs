@@ -0,0 +1,94 @@
+use std::{ffi::OsString, thread, time::Duration};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn make_keystore_mux() -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        r##"agent_sock_paths = [{ keystore = {} }]"##,
+        None::<OsString>,
+    )
+}
+
+#[test]
+fn add_and_sign_with_keystore() -> TestResult {
+    let mux_agent = make_keystore_mux()?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    for key in keys::PRIVATE {
+        mux_agent.add(key)?;
+    }
+
+    let keys_in_agent = mux_agent.list()?;
+    for key in keys::PUBLIC {
+        assert!(keys_in_agent.iter().any(|v| v == key));
+        mux_agent.sign_and_verify(temp_dir.path(), key)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn remove_from_keystore() -> TestResult {
+    let mux_agent = make_keystore_mux()?;
+    for key in keys::PRIVATE {
+        mux_agent.add(key)?;
+    }
+
+    assert!(mux_agent.ssh_add(["-D"])?);
+    assert!(mux_agent.list()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn keystore_key_lifetime() -> TestResult {
+    let mux_agent = SshAgentInstance::new_mux(
+        r##"agent_sock_paths = [{ keystore = { max_lifetime = 1 } }]"##,
+        None::<OsString>,
+    )?;
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+    assert_eq!(mux_agent.list()?.len(), 1);
+
+    thread::sleep(Duration::from_millis(1500));
+    assert!(mux_agent.list()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn keystore_alongside_upstream_agent() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add(keys::TEST_KEY_RSA)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ keystore = {{}} }}, "{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    mux_agent.add(keys::TEST_KEY_ED25519)?;
+
+    // Keys are offered in the order of the upstreams
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_RSA_PUB]
+    );
+    // Keys added through the mux only go to the keystore
+    assert_eq!(upstream.list()?, [keys::TEST_KEY_RSA_PUB]);
+
+    Ok(())
+}
+
+#[test]
+fn add_without_keystore_refused() -> TestResult {
+    let mux_agent = SshAgentInstance::new_mux("", None::<OsString>)?;
+
+    assert!(mux_agent.add(keys::TEST_KEY_ED25519).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::{ffi::OsString, fs, os::unix::fs::PermissionsExt, sync::Mutex, thread, time::Duration};
+
+use duct::unix::HandleExt;
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Held by each test, because they point `SSH_ASKPASS` at their own scripts
+static ASKPASS: Mutex<()> = Mutex::new(());
+
+fn mux_with_lock(upstream: &SshAgentInstance, lock: &str) -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        &format!(
+            "agent_sock_paths = [\"{}\"]\n[lock]\n{}",
+            upstream.sock_path.display(),
+            lock,
+        ),
+        None::<OsString>,
+    )
+}
+
+#[test]
+fn lock_and_unlock() -> TestResult {
+    let _askpass = ASKPASS.lock().unwrap_or_else(|e| e.into_inner());
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    // ssh-add asks for lock passphrases with askpass when it has no terminal
+    let askpass = temp_dir.path().join("askpass");
+    let set_passphrase = |passphrase: &str| {
+        fs::write(&askpass, format!("#!/bin/sh\necho {passphrase}\n"))?;
+        fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))
+    };
+    set_passphrase("secret")?;
+    std::env::set_var("SSH_ASKPASS", &askpass);
+    std::env::set_var("SSH_ASKPASS_REQUIRE", "force");
+
+    let mux_agent = mux_with_lock(&agent, "")?;
+    assert!(mux_agent.ssh_add(["-x"])?);
+    assert!(!mux_agent.ssh_add(["-x"])?);
+    assert_eq!(mux_agent.list()?, Vec::<String>::new());
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    set_passphrase("wrong")?;
+    assert!(!mux_agent.ssh_add(["-X"])?);
+    set_passphrase("secret")?;
+    assert!(mux_agent.ssh_add(["-X"])?);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    // Locked automatically, and only unlocked once the user confirms it
+    let mux_agent = mux_with_lock(&agent, "idle_timeout = 1\nlist_while_locked = true")?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    thread::sleep(Duration::from_millis(1200));
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    fs::write(
+        &askpass,
+        "#!/bin/sh\ntest \"$SSH_ASKPASS_PROMPT\" != confirm && echo secret\n",
+    )?;
+    assert!(!mux_agent.ssh_add(["-X"])?);
+    set_passphrase("anything")?;
+    assert!(mux_agent.ssh_add(["-X"])?);
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
+
+#[test]
+fn unlock_subcommand_needs_no_confirmation() -> TestResult {
+    let _askpass = ASKPASS.lock().unwrap_or_else(|e| e.into_inner());
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    // Gives a passphrase, but never confirms, as on a host without a display
+    let askpass = temp_dir.path().join("askpass");
+    fs::write(
+        &askpass,
+        "#!/bin/sh\ntest \"$SSH_ASKPASS_PROMPT\" != confirm && echo anything\n",
+    )?;
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))?;
+    std::env::set_var("SSH_ASKPASS", &askpass);
+    std::env::set_var("SSH_ASKPASS_REQUIRE", "force");
+
+    let mux_agent = mux_with_lock(&agent, "idle_timeout = 1")?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    thread::sleep(Duration::from_millis(1200));
+    assert!(!mux_agent.ssh_add(["-X"])?);
+    assert!(mux_agent.mux_command(["unlock"])?.0);
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
+
+#[test]
+fn lock_survives_reload() -> TestResult {
+    const SIGHUP: std::ffi::c_int = 1;
+    let _askpass = ASKPASS.lock().unwrap_or_else(|e| e.into_inner());
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    let askpass = temp_dir.path().join("askpass");
+    fs::write(&askpass, "#!/bin/sh\necho secret\n")?;
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))?;
+    std::env::set_var("SSH_ASKPASS", &askpass);
+    std::env::set_var("SSH_ASKPASS_REQUIRE", "force");
+
+    // Upstream given as an argument, because the harness's config file is gone by reload
+    let mux_agent = SshAgentInstance::new_mux("", [agent.sock_path.as_os_str().to_owned()])?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    assert!(mux_agent.ssh_add(["-x"])?);
+
+    mux_agent.handle.send_signal(SIGHUP)?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(mux_agent.list()?, Vec::<String>::new());
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    assert!(mux_agent.ssh_add(["-X"])?);
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
+
+#[test]
+fn lock_and_unlock_subcommands() -> TestResult {
+    let _askpass = ASKPASS.lock().unwrap_or_else(|e| e.into_inner());
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    let askpass = temp_dir.path().join("askpass");
+    let set_passphrase = |passphrase: &str| {
+        fs::write(&askpass, format!("#!/bin/sh\necho {passphrase}\n"))?;
+        fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))
+    };
+    set_passphrase("secret")?;
+    std::env::set_var("SSH_ASKPASS", &askpass);
+    std::env::set_var("SSH_ASKPASS_REQUIRE", "force");
+
+    let mux_agent = mux_with_lock(&agent, "")?;
+    assert!(mux_agent.mux_command(["lock"])?.0);
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    set_passphrase("wrong")?;
+    assert!(!mux_agent.mux_command(["unlock"])?.0);
+    set_passphrase("secret")?;
+    assert!(mux_agent.mux_command(["unlock"])?.0);
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}
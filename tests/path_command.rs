@@ -0,0 +1,62 @@
+use std::{ffi::OsString, fs, path::Path};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn invocations(dir: &Path) -> usize {
+    fs::read_to_string(dir.join("invocations")).map_or(0, |i| i.lines().count())
+}
+
+#[test]
+fn socket_path_from_command() -> TestResult {
+    let first = SshAgentInstance::new_openssh()?;
+    first.add(keys::TEST_KEY_RSA)?;
+    let second = SshAgentInstance::new_openssh()?;
+    second.add(keys::TEST_KEY_ED25519)?;
+
+    // The command prints whatever path is in `current`, and counts how often it's run
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let current = dir.path().join("current");
+    fs::write(&current, format!("{}\n", first.sock_path.display()))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ path_command = ["sh", "-c", "echo >> '{}/invocations'; cat '{}'"] }}]"##,
+            dir.path().display(),
+            current.display(),
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_RSA_PUB]);
+    assert_eq!(invocations(dir.path()), 1);
+
+    // Re-resolved once the first agent's socket stops working
+    fs::write(&current, format!("{}\n", second.sock_path.display()))?;
+    drop(first);
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    assert_eq!(invocations(dir.path()), 2);
+
+    Ok(())
+}
+
+#[test]
+fn failing_path_command_ignored() -> TestResult {
+    let upstream = SshAgentInstance::new_openssh()?;
+    upstream.add(keys::TEST_KEY_ECDSA)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ path_command = ["false"] }}, "{}"]"##,
+            upstream.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ECDSA_PUB]);
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use std::ffi::OsString;
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn mux_with_namespaces(
+    upstream: &SshAgentInstance,
+    namespaces: &str,
+) -> std::io::Result<SshAgentInstance> {
+    SshAgentInstance::new_mux(
+        &format!(
+            r##"
+            agent_sock_paths = ["{}"]
+
+            [[purpose_policies]]
+            keys = ["{}"]
+            namespaces = {}
+            "##,
+            upstream.sock_path.display(),
+            keys::TEST_KEY_ED25519_PUB,
+            namespaces,
+        ),
+        None::<OsString>,
+    )
+}
+
+#[test]
+fn sshsig_namespaces_restricted() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    agent.add(keys::TEST_KEY_RSA)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    // `sign_and_verify` signs in the "file" namespace
+    let mux_agent = mux_with_namespaces(&agent, r#"["git", "file"]"#)?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    let mux_agent = mux_with_namespaces(&agent, r#"["git"]"#)?;
+    // Restricted keys are still listed, because servers can't tell what they'll be used for
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ED25519_PUB, keys::TEST_KEY_RSA_PUB]
+    );
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    // Keys without a policy are unaffected
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_RSA_PUB)?;
+
+    Ok(())
+}
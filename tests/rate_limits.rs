@@ -0,0 +1,46 @@
+use std::{ffi::OsString, fs, thread, time::Duration};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn signatures_rate_limited_per_key() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    agent.add(keys::TEST_KEY_ECDSA)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let hook_log = temp_dir.path().join("hook.log");
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"agent_sock_paths = ["{}"]
+            [rate_limits]
+            per_key = {{ burst = 2, per_minute = 0 }}
+            on_limit = ["sh", "-c", "echo $SSH_AGENT_MUX_LIMIT >> {}"]"#,
+            agent.sock_path.display(),
+            hook_log.display(),
+        ),
+        None::<OsString>,
+    )?;
+
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+    // Other keys have their own limits
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ECDSA_PUB)?;
+
+    // The hook only runs for the first request over the limit
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(fs::read_to_string(&hook_log)?, "key\n");
+
+    Ok(())
+}
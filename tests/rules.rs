@@ -0,0 +1,122 @@
+use std::{ffi::OsString, fs, os::unix::fs::PermissionsExt};
+
+use harness::SshAgentInstance;
+use ssh_agent_lib::{
+    agent::Session,
+    client::Client,
+    proto::{PublicCredential, SignRequest},
+    ssh_key::PublicKey,
+};
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn rules_decide_in_order() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_RSA)?;
+    agent.add(keys::TEST_KEY_ECDSA)?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+
+    // Confirms only the Ed25519 key
+    let askpass = temp_dir.path().join("askpass");
+    fs::write(
+        &askpass,
+        "#!/bin/sh\n[ \"$SSH_ASKPASS_PROMPT\" = confirm ] || exit 1\ncase \"$1\" in *ed25519*) exit 0;; *) exit 1;; esac\n",
+    )?;
+    fs::set_permissions(&askpass, fs::Permissions::from_mode(0o755))?;
+    // Inherited by the mux
+    std::env::set_var("SSH_ASKPASS", &askpass);
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"agent_sock_paths = ["{sock}"]
+            [[rules]]
+            keys = ["{rsa}"]
+            action = "hide"
+            [[rules]]
+            name = "no file signatures"
+            namespaces = ["file"]
+            keys = ["{ecdsa}"]
+            action = "deny"
+            [[rules]]
+            action = "log"
+            [[rules]]
+            purposes = ["sshsig"]
+            action = "confirm""#,
+            sock = agent.sock_path.display(),
+            rsa = keys::TEST_KEY_RSA_PUB.trim(),
+            ecdsa = keys::TEST_KEY_ECDSA_PUB.trim(),
+        ),
+        None::<OsString>,
+    )?;
+
+    assert_eq!(
+        mux_agent.list()?,
+        [keys::TEST_KEY_ECDSA_PUB, keys::TEST_KEY_ED25519_PUB]
+    );
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ECDSA_PUB)
+        .is_err());
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    // Not confirmed
+    fs::write(&askpass, "#!/bin/sh\nexit 1\n")?;
+    assert!(mux_agent
+        .sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn host_key_rules_need_verified_binding() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r#"agent_sock_paths = ["{sock}"]
+            [[rules]]
+            host_keys = ["{host_key}"]
+            action = "allow"
+            [[rules]]
+            action = "deny""#,
+            sock = agent.sock_path.display(),
+            host_key = keys::TEST_KEY_ED25519_PUB.trim(),
+        ),
+        None::<OsString>,
+    )?;
+    let mut client = Client::new(tokio::net::UnixStream::connect(&mux_agent.sock_path).await?);
+    let sign_request = SignRequest {
+        credential: PublicCredential::Key(
+            PublicKey::from_openssh(keys::TEST_KEY_ED25519_PUB)?
+                .key_data()
+                .clone(),
+        ),
+        data: b"data".to_vec(),
+        flags: 0,
+    };
+
+    // A binding with a bad signature doesn't count
+    assert!(client
+        .extension(harness::session_bind(
+            keys::TEST_KEY_ED25519,
+            b"another session ID"
+        )?)
+        .await
+        .is_err());
+    assert!(client.sign(sign_request.clone()).await.is_err());
+
+    client
+        .extension(harness::session_bind(
+            keys::TEST_KEY_ED25519,
+            b"session ID",
+        )?)
+        .await?;
+    client.sign(sign_request).await?;
+
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use std::{
+    ffi::OsString,
+    fs::{self, Permissions},
+    os::unix::fs::{symlink, PermissionsExt},
+};
+
+use harness::SshAgentInstance;
+
+mod harness;
+mod keys;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn sockets_others_could_replace_refused() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+
+    let dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    let shared = dir.path().join("shared");
+    fs::create_dir(&shared)?;
+    fs::set_permissions(&shared, Permissions::from_mode(0o777))?;
+    symlink(&agent.sock_path, shared.join("agent.sock"))?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}/agent.sock"]"##,
+            shared.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert!(mux_agent.list()?.is_empty());
+
+    // Unless explicitly trusted
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = [{{ unverified = "{}/agent.sock" }}]"##,
+            shared.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    // Sticky directories, like /tmp, are fine
+    fs::set_permissions(&shared, Permissions::from_mode(0o1777))?;
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            r##"agent_sock_paths = ["{}/agent.sock"]"##,
+            shared.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+
+    Ok(())
+}
+
+#[test]
+fn peer_credentials_verified() -> TestResult {
+    let agent = SshAgentInstance::new_openssh()?;
+    agent.add(keys::TEST_KEY_ED25519)?;
+
+    let mux_agent = SshAgentInstance::new_mux(
+        &format!(
+            "verify_peer_credentials = true\nagent_sock_paths = [\"{}\"]",
+            agent.sock_path.display()
+        ),
+        None::<OsString>,
+    )?;
+    assert_eq!(mux_agent.list()?, [keys::TEST_KEY_ED25519_PUB]);
+    let temp_dir = tempfile::tempdir_in(env!("CARGO_TARGET_TMPDIR"))?;
+    mux_agent.sign_and_verify(temp_dir.path(), keys::TEST_KEY_ED25519_PUB)?;
+
+    Ok(())
+}